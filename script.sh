@@ -1,9 +1,13 @@
 #!/bin/bash
 
+# Arguments are KEY=VALUE settings for this run, such as
+# COSMOS_CONNECTION_STRING=... or ROCKET_PROFILE=prod. They are exported
+# rather than written to .env so that repeated starts do not pile up
+# duplicate (and stale) entries in it.
 for arg in "$@"; do
-    echo "$arg" >> .env
+    export "$arg"
 done
 
 cargo test
 
-cinema-score
+exec cinema-score
@@ -0,0 +1,328 @@
+use crate::audit::{self, AuditAction, AuditEntry, TargetKind};
+use crate::auth::{Authenticated, Scope};
+use crate::csrf::CsrfVerified;
+use crate::error::ApiError;
+use crate::events::MovieEvent;
+use crate::jwt;
+use crate::validation::{self, FieldErrors};
+use crate::{database_error, expired_session_cookies, rating_update, session_cookies, user_by_name, GenericJsonResponse, ServerData, User};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, put, State};
+use serde_json::json;
+use std::net::IpAddr;
+use tracing::error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct PasswordChangeRequest {
+    /// Left out by users who signed up through an identity provider and
+    /// are setting their first password.
+    #[serde(default)]
+    old_password: String,
+    new_password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct UsernameChangeRequest {
+    name: String,
+}
+
+/// `email: null` removes the address.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct EmailChangeRequest {
+    /// Left out by users who signed up through an identity provider and
+    /// have not set a password.
+    #[serde(default)]
+    password: String,
+    email: Option<String>,
+}
+
+/// What happens to the movies a user created when their account is deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CreatedMoviesPolicy {
+    Delete,
+    Reassign,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct AccountDeleteRequest {
+    password: String,
+    created_movies: CreatedMoviesPolicy,
+    reassign_to: Option<String>,
+}
+
+#[utoipa::path(
+    tag = "account",
+    request_body = PasswordChangeRequest,
+    responses(
+        (status = 200, description = "Changed; other sessions are logged out"),
+        (status = 403, description = "Wrong password", body = crate::api::Problem),
+        (status = 422, description = "The new password is too weak", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[put("/users/me/password", format = "json", data = "<request>")]
+pub async fn change_password(request: Json<PasswordChangeRequest>, client_ip: Option<IpAddr>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let user = auth.user;
+    if !user.password_unset && user.password != request.old_password {
+        return GenericJsonResponse::error(Status::Forbidden, "Wrong password");
+    }
+    let mut errors = FieldErrors::default();
+    errors.add("new_password", validation::validate_password(&request.new_password, &user.name));
+    if !errors.is_empty() {
+        return errors.into_response();
+    }
+
+    // A new session id logs out every other browser still holding the old
+    // one; in JWT mode, revoking refresh tokens does the same once the
+    // current access tokens expire.
+    let uuid = Uuid::new_v4().to_string();
+    let update = doc! {"$set": {"password": &request.new_password, "uuid": &uuid, "password_unset": false}};
+    if let Err(error) = server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        return database_error(error);
+    }
+    if let Err(response) = jwt::revoke_user_tokens(user.id, server_data).await {
+        return response;
+    }
+    audit::record(AuditEntry::new(AuditAction::PasswordChanged, &user, client_ip).target(TargetKind::User, user.id), server_data).await;
+    let mut response = GenericJsonResponse::ok(&json!({"message": "Password changed"}));
+    response.cookies = session_cookies(&user.name, &uuid);
+    response
+}
+
+#[utoipa::path(
+    tag = "account",
+    request_body = UsernameChangeRequest,
+    responses(
+        (status = 200, description = "Changed"),
+        (status = 409, description = "The name is taken", body = crate::api::Problem),
+        (status = 422, description = "The name is invalid", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[put("/users/me/name", format = "json", data = "<request>")]
+pub async fn change_username(request: Json<UsernameChangeRequest>, client_ip: Option<IpAddr>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let user = auth.user;
+    let name = validation::normalize_username(&request.name);
+    let name_key = validation::username_key(&name);
+    let mut errors = FieldErrors::default();
+    errors.add("name", validation::validate_username(&name));
+    if !errors.is_empty() {
+        return errors.into_response();
+    }
+    let existing = doc! {"_id": {"$ne": user.id}, "$or": [{"name_key": &name_key}, {"name": &name}]};
+    match server_data.users.find_one(existing, None).await {
+        Ok(Some(_)) => return ApiError::UsernameTaken.into(),
+        Ok(None) => (),
+        Err(error) => return database_error(error),
+    }
+
+    let update = doc! {"$set": {"name": &name, "name_key": &name_key}};
+    match server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        Ok(_) => (),
+        Err(error) if validation::is_duplicate_key(&error) => return ApiError::UsernameTaken.into(),
+        Err(error) => return database_error(error),
+    }
+    let update = doc! {"$set": {"actor_name": &name}};
+    if let Err(error) = server_data.activities.update_many(doc! {"actor": user.id}, update, None).await {
+        error!(?error, "Failed to rename activities");
+    }
+    let entry = AuditEntry::new(AuditAction::UsernameChanged, &user, client_ip)
+        .target(TargetKind::User, user.id)
+        .changes(doc! {"name": &user.name}, doc! {"name": &name});
+    audit::record(entry, server_data).await;
+    let mut response = GenericJsonResponse::ok(&json!({"message": "Username changed", "name": name}));
+    response.cookies = session_cookies(&name, &user.uuid);
+    response
+}
+
+/// The password is required because whoever controls the email can reset it.
+#[utoipa::path(
+    tag = "account",
+    request_body = EmailChangeRequest,
+    responses(
+        (status = 200, description = "Changed"),
+        (status = 403, description = "Wrong password", body = crate::api::Problem),
+        (status = 409, description = "The address is taken", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[put("/users/me/email", format = "json", data = "<request>")]
+pub async fn change_email(request: Json<EmailChangeRequest>, client_ip: Option<IpAddr>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let user = auth.user;
+    if !user.password_unset && user.password != request.password {
+        return GenericJsonResponse::error(Status::Forbidden, "Wrong password");
+    }
+    let email = request.email.as_deref().map(validation::normalize_email).filter(|email| !email.is_empty());
+    let update = match &email {
+        Some(email) => {
+            let mut errors = FieldErrors::default();
+            errors.add("email", validation::validate_email(email));
+            if !errors.is_empty() {
+                return errors.into_response();
+            }
+            match server_data.users.find_one(doc! {"_id": {"$ne": user.id}, "email": email}, None).await {
+                Ok(Some(_)) => return ApiError::EmailTaken.into(),
+                Ok(None) => (),
+                Err(error) => return database_error(error),
+            }
+            doc! {"$set": {"email": email}}
+        }
+        None => doc! {"$unset": {"email": ""}},
+    };
+    match server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        Ok(_) => (),
+        Err(error) if validation::is_duplicate_key(&error) => return ApiError::EmailTaken.into(),
+        Err(error) => return database_error(error),
+    }
+    // Links mailed to the old address stop working.
+    if let Err(error) = server_data.password_resets.delete_many(doc! {"user_id": user.id}, None).await {
+        return database_error(error);
+    }
+    let entry = AuditEntry::new(AuditAction::EmailChanged, &user, client_ip)
+        .target(TargetKind::User, user.id)
+        .changes(doc! {"email": &user.email}, doc! {"email": &email});
+    audit::record(entry, server_data).await;
+    GenericJsonResponse::ok(&json!({"message": "Email changed", "email": email}))
+}
+
+/// Takes the user's ratings out of every movie they rated, skipping movies
+/// that are about to be deleted anyway.
+async fn remove_ratings(user: &User, skip: &[ObjectId], server_data: &ServerData) -> Result<(), GenericJsonResponse> {
+    for &(movie_id, rating) in &user.movie_ratings {
+        if skip.contains(&movie_id) {
+            continue;
+        }
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+        let filter = doc! {"_id": movie_id, "num_ratings": {"$gt": 0}};
+        let movie = match server_data.movies.find_one_and_update(filter, rating_update(-1, -rating), options).await {
+            Ok(Some(movie)) => movie,
+            Ok(None) => continue,
+            Err(error) => return Err(database_error(error)),
+        };
+        server_data.events.publish(MovieEvent::RatingChanged { movie_id, avg_rating: movie.avg_rating, num_ratings: movie.num_ratings });
+    }
+    Ok(())
+}
+
+/// Deletes movies with their images, and takes them out of lists, feeds and
+/// everyone's ratings.
+pub async fn delete_movies(ids: &[ObjectId], server_data: &ServerData) -> Result<(), ApiError> {
+    let filter = doc! {"_id": {"$in": ids}};
+    let mut cursor = server_data.movies.find(filter.clone(), None).await?;
+    while cursor.advance().await? {
+        let Ok(movie) = cursor.deserialize_current() else {
+            continue;
+        };
+        if movie.image_url.is_empty() {
+            continue;
+        }
+        let blob_client = server_data
+            .blob_client_builder
+            .clone()
+            .blob_client(&server_data.container_name, movie.image_url.clone());
+        if let Err(error) = blob_client.delete().await {
+            error!(?error, image = %movie.image_url, "Failed to delete image");
+        }
+    }
+    server_data.movies.delete_many(filter, None).await?;
+    for &movie_id in ids {
+        server_data.events.publish(MovieEvent::Deleted(movie_id));
+    }
+    let update = doc! {"$pull": {"movie_ratings": {"$elemMatch": {"$in": ids}}}};
+    server_data.users.update_many(doc! {"movie_ratings": {"$elemMatch": {"$elemMatch": {"$in": ids}}}}, update, None).await?;
+    let update = doc! {"$pull": {"movie_ids": {"$in": ids}}};
+    server_data.lists.update_many(doc! {}, update, None).await?;
+    server_data.activities.delete_many(doc! {"movie_id": {"$in": ids}}, None).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    tag = "account",
+    request_body = AccountDeleteRequest,
+    responses(
+        (status = 200, description = "Deleted"),
+        (status = 403, description = "Wrong password, or no password set yet", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[delete("/users/me", format = "json", data = "<request>")]
+pub async fn delete_account(request: Json<AccountDeleteRequest>, client_ip: Option<IpAddr>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let user = auth.user;
+    if user.password_unset {
+        return GenericJsonResponse::error(Status::Forbidden, "Set a password before deleting your account");
+    }
+    if !user.password_matches(&request.password) {
+        return GenericJsonResponse::error(Status::Forbidden, "Wrong password");
+    }
+    let heir = match (request.created_movies, &request.reassign_to) {
+        (CreatedMoviesPolicy::Delete, _) => None,
+        (CreatedMoviesPolicy::Reassign, None) => {
+            return GenericJsonResponse::error(Status::UnprocessableEntity, "reassign_to is required to reassign movies");
+        }
+        (CreatedMoviesPolicy::Reassign, Some(name)) => match user_by_name(name, &server_data.users).await {
+            Ok(Some(heir)) if heir.id != user.id => Some(heir),
+            Ok(Some(_)) => return GenericJsonResponse::error(Status::UnprocessableEntity, "Cannot reassign movies to yourself"),
+            Ok(None) => return GenericJsonResponse::error(Status::NotFound, "User to reassign movies to not found"),
+            Err(error) => return database_error(error),
+        },
+    };
+
+    let deleted_movies = if heir.is_none() { user.created_movies.clone() } else { Vec::new() };
+    if let Err(response) = remove_ratings(&user, &deleted_movies, server_data).await {
+        return response;
+    }
+    let result = match heir {
+        Some(heir) => {
+            let update = doc! {"$addToSet": {"created_movies": {"$each": &user.created_movies}}};
+            server_data.users.update_one(doc! {"_id": heir.id}, update, None).await.map(|_| ()).map_err(database_error)
+        }
+        None => delete_movies(&deleted_movies, server_data).await.map_err(Into::into),
+    };
+    if let Err(response) = result {
+        return response;
+    }
+
+    let cleanup = async {
+        server_data.lists.delete_many(doc! {"owner": user.id}, None).await?;
+        server_data.activities.delete_many(doc! {"actor": user.id}, None).await?;
+        server_data.refresh_tokens.delete_many(doc! {"user_id": user.id}, None).await?;
+        server_data.api_tokens.delete_many(doc! {"user_id": user.id}, None).await?;
+        server_data.password_resets.delete_many(doc! {"user_id": user.id}, None).await?;
+        server_data.two_factor_challenges.delete_many(doc! {"user_id": user.id}, None).await?;
+        let update = doc! {"$pull": {"following": user.id}};
+        server_data.users.update_many(doc! {"following": user.id}, update, None).await?;
+        server_data.users.delete_one(doc! {"_id": user.id}, None).await
+    };
+    if let Err(error) = cleanup.await {
+        return database_error(error);
+    }
+    // The actor id stays in the log so entries can still be tied together.
+    let entry = AuditEntry::new(AuditAction::AccountDeleted, &user, client_ip)
+        .target(TargetKind::User, user.id)
+        .changes(doc! {"name": &user.name, "email": &user.email, "created_movies": &user.created_movies}, doc! {});
+    audit::record(entry, server_data).await;
+
+    let mut response = GenericJsonResponse::ok(&json!({"message": "Account deleted", "redirectPath": "/movies"}));
+    response.cookies = expired_session_cookies();
+    response
+}
@@ -0,0 +1,288 @@
+use crate::error::ApiError;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::Request;
+use rocket::route::Route;
+use rocket::{Data, Response};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+pub const BASE: &str = "/api/v1";
+/// The deepest page a paginated listing serves. Further pages make Mongo
+/// skip over too many documents.
+const MAX_PAGE: u64 = 1000;
+/// When the unversioned paths were deprecated, sent in the `Deprecation`
+/// header as an RFC 9745 date (2026-10-18).
+const DEPRECATED_AT: i64 = 1_792_281_600;
+
+/// Unversioned paths whose `/api/v1` successor is not simply the same path
+/// under the new prefix. `<_>` stands for one segment, carried over in order.
+const RENAMED: &[(Method, &str, Method, &str)] = &[
+    (Method::Post, "/api/add-movie", Method::Post, "/api/v1/movies"),
+    (Method::Get, "/api/movies/<_>", Method::Get, "/api/v1/users/<_>/movies"),
+    (Method::Get, "/api/thumbnail/<_>", Method::Get, "/api/v1/thumbnails/<_>"),
+    (Method::Post, "/api/login", Method::Post, "/api/v1/sessions"),
+    (Method::Post, "/api/login/2fa", Method::Post, "/api/v1/sessions/2fa"),
+    (Method::Post, "/api/auth/refresh", Method::Post, "/api/v1/sessions/refresh"),
+    (Method::Post, "/logout", Method::Delete, "/api/v1/sessions/current"),
+];
+
+/// Paths under `/api` that are not part of the API.
+const UNVERSIONED: &[&str] = &[crate::throttle::LIMITED_PATH];
+
+/// The `/api/v1` route an unversioned request is served by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Legacy {
+    method: Method,
+    path: String,
+}
+
+/// How many entries come before `page` of a listing, or 400 for a page out
+/// of range.
+pub fn page_offset(page: u64, page_size: i64) -> Result<u64, ApiError> {
+    page.checked_mul(page_size as u64)
+        .filter(|_| page <= MAX_PAGE)
+        .ok_or_else(|| ApiError::Other(Status::BadRequest, format!("`page` must be at most {}", MAX_PAGE)))
+}
+
+/// Fills `<_>` in `to` with the segments of `path` that matched `<_>` in `from`.
+fn rewrite(from: &str, to: &str, path: &str) -> Option<String> {
+    let pattern: Vec<&str> = from.split('/').collect();
+    let segments: Vec<&str> = path.split('/').collect();
+    if pattern.len() != segments.len() {
+        return None;
+    }
+    let mut captured = Vec::new();
+    for (expected, segment) in pattern.iter().zip(&segments) {
+        match *expected {
+            "<_>" if !segment.is_empty() => captured.push(*segment),
+            "<_>" => return None,
+            literal if literal == *segment => (),
+            _ => return None,
+        }
+    }
+    let mut captured = captured.into_iter();
+    let rewritten: Vec<&str> = to.split('/').map(|part| if part == "<_>" { captured.next().unwrap_or_default() } else { part }).collect();
+    Some(rewritten.join("/"))
+}
+
+/// Where an unversioned request is served now, if it is one.
+fn legacy(method: Method, path: &str) -> Option<Legacy> {
+    for &(from_method, from, to_method, to) in RENAMED {
+        if from_method == method {
+            if let Some(path) = rewrite(from, to, path) {
+                return Some(Legacy { method: to_method, path });
+            }
+        }
+    }
+    if UNVERSIONED.contains(&path) || path == BASE || path.starts_with("/api/v1/") {
+        return None;
+    }
+    path.strip_prefix("/api/").map(|rest| Legacy {
+        method,
+        path: format!("{}/{}", BASE, rest),
+    })
+}
+
+#[derive(Default)]
+struct Aliased(Option<Legacy>);
+
+/// Serves the unversioned paths from before `/api/v1` by rerouting them to
+/// their successors, and marks the responses with `Deprecation` and a
+/// `successor-version` link. Must be attached before anything that looks at
+/// the path, such as the rate limiter.
+pub struct LegacyAliases;
+
+#[rocket::async_trait]
+impl Fairing for LegacyAliases {
+    fn info(&self) -> Info {
+        Info {
+            name: "Legacy API aliases",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(legacy) = legacy(request.method(), request.uri().path().as_str()) else {
+            return;
+        };
+        let uri = match request.uri().query() {
+            Some(query) => format!("{}?{}", legacy.path, query),
+            None => legacy.path.clone(),
+        };
+        let Ok(uri) = Origin::parse_owned(uri) else {
+            return;
+        };
+        request.set_method(legacy.method);
+        request.set_uri(uri);
+        request.local_cache(|| Aliased(Some(legacy)));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Aliased(Some(legacy)) = request.local_cache(Aliased::default) else {
+            return;
+        };
+        // Unknown unversioned paths are plain 404s, not deprecated routes;
+        // the static file server may have had a look at them, though.
+        if !request.route().is_some_and(|route| route.uri.as_str().starts_with(BASE)) {
+            return;
+        }
+        response.set_raw_header("Deprecation", format!("@{}", DEPRECATED_AT));
+        response.set_raw_header("Link", format!("<{}>; rel=\"successor-version\"", legacy.path));
+    }
+}
+
+/// The body of every error response; see [`crate::error::ApiError`]. Only
+/// describes it for the OpenAPI document.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct Problem {
+    #[schema(example = "about:blank")]
+    r#type: String,
+    #[schema(example = "Conflict")]
+    title: String,
+    #[schema(example = 409)]
+    status: u16,
+    #[schema(example = "Movie already exists")]
+    detail: String,
+    /// Stable, for telling errors apart.
+    #[schema(example = "movie_exists")]
+    code: String,
+    /// Repeats `detail` for older clients.
+    error: String,
+    /// Messages per request field, for validation errors.
+    fields: Option<std::collections::BTreeMap<String, Vec<String>>>,
+}
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        let bearer = HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some("An API token (`cs_...`) or, in JWT mode, an access token"))
+            .build();
+        components.add_security_scheme("bearer", SecurityScheme::Http(bearer));
+        // The `username` cookie goes along with `id`.
+        components.add_security_scheme("session", SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("id"))));
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Cinema Score API", description = "Rate movies, keep lists and follow other users."),
+    servers((url = "/api/v1")),
+    modifiers(&SecuritySchemes),
+    components(schemas(Problem)),
+    tags(
+        (name = "movies"),
+        (name = "sessions", description = "Logging in and out"),
+        (name = "users"),
+        (name = "account", description = "The caller's own account; API tokens need the `admin` scope"),
+        (name = "lists"),
+        (name = "feed"),
+        (name = "tokens", description = "Personal API tokens"),
+        (name = "admin"),
+    ),
+    paths(
+        crate::get_movies,
+        crate::events::movie_events,
+        crate::add_movie,
+        crate::update_movie,
+        crate::rate_movie,
+        crate::delete_own_movie,
+        crate::get_thumbnail,
+        crate::get_movies_by_username,
+        crate::login,
+        crate::logout,
+        crate::two_factor::complete_challenge,
+        crate::jwt::refresh,
+        crate::create_user,
+        crate::password_reset::forgot_password,
+        crate::password_reset::reset_password,
+        crate::profiles::get_profile,
+        crate::profiles::update_privacy,
+        crate::feed::follow_user,
+        crate::feed::unfollow_user,
+        crate::feed::get_feed,
+        crate::account::change_password,
+        crate::account::change_username,
+        crate::account::change_email,
+        crate::account::delete_account,
+        crate::two_factor::enroll,
+        crate::two_factor::verify_enrollment,
+        crate::two_factor::disable,
+        crate::lists::create_list,
+        crate::lists::get_public_lists,
+        crate::lists::get_my_lists,
+        crate::lists::get_list,
+        crate::lists::update_list,
+        crate::lists::delete_list,
+        crate::lists::add_list_movie,
+        crate::lists::remove_list_movie,
+        crate::lists::reorder_list,
+        crate::tokens::create_token,
+        crate::tokens::get_tokens,
+        crate::tokens::revoke_token,
+        crate::audit::get_audit_log,
+    )
+)]
+pub struct ApiDoc;
+
+/// The OpenAPI document at `/api/v1/openapi.json` and Swagger UI at
+/// `/api/v1/docs/`, served from assets built into the binary.
+pub fn docs() -> Vec<Route> {
+    SwaggerUi::new("/api/v1/docs/<_..>").url("/api/v1/openapi.json", ApiDoc::openapi()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{legacy, page_offset, rewrite, ApiDoc, Legacy, MAX_PAGE};
+    use rocket::http::Method;
+    use utoipa::OpenApi;
+
+    #[test]
+    fn test_page_offset() {
+        assert_eq!(page_offset(0, 50).unwrap(), 0);
+        assert_eq!(page_offset(2, 50).unwrap(), 100);
+        assert!(page_offset(MAX_PAGE, 50).is_ok());
+        assert!(page_offset(MAX_PAGE + 1, 50).is_err());
+        assert!(page_offset(u64::MAX, 50).is_err());
+    }
+
+    fn moved(method: Method, path: &str) -> Option<Legacy> {
+        Some(Legacy { method, path: path.to_string() })
+    }
+
+    #[test]
+    fn test_rewrite() {
+        assert_eq!(rewrite("/api/movies/<_>", "/api/v1/users/<_>/movies", "/api/movies/alice"), Some("/api/v1/users/alice/movies".to_string()));
+        assert_eq!(rewrite("/api/movies/<_>", "/api/v1/users/<_>/movies", "/api/movies"), None);
+        assert_eq!(rewrite("/api/movies/<_>", "/api/v1/users/<_>/movies", "/api/movies/"), None);
+        assert_eq!(rewrite("/api/login", "/api/v1/sessions", "/api/logout"), None);
+    }
+
+    #[test]
+    fn test_legacy_paths() {
+        assert_eq!(legacy(Method::Post, "/api/add-movie"), moved(Method::Post, "/api/v1/movies"));
+        assert_eq!(legacy(Method::Get, "/api/movies/alice"), moved(Method::Get, "/api/v1/users/alice/movies"));
+        assert_eq!(legacy(Method::Delete, "/api/movies/65a1f0c2e4b0a1b2c3d4e5f6"), moved(Method::Delete, "/api/v1/movies/65a1f0c2e4b0a1b2c3d4e5f6"));
+        assert_eq!(legacy(Method::Post, "/logout"), moved(Method::Delete, "/api/v1/sessions/current"));
+        assert_eq!(legacy(Method::Get, "/api/lists/mine"), moved(Method::Get, "/api/v1/lists/mine"));
+        assert_eq!(legacy(Method::Get, "/api/v1/movies"), None);
+        assert_eq!(legacy(Method::Get, "/api/rate-limited"), None);
+        assert_eq!(legacy(Method::Get, "/movies"), None);
+    }
+
+    #[test]
+    fn test_openapi_paths() {
+        let doc = ApiDoc::openapi();
+        let movies = doc.paths.paths.get("/movies").expect("movies path");
+        assert!(movies.get.is_some() && movies.post.is_some());
+        assert!(doc.paths.paths.contains_key("/users/{username}/movies"));
+        assert!(doc.paths.paths.contains_key("/lists/{slug}/movies/{movie_id}"));
+    }
+}
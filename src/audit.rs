@@ -0,0 +1,281 @@
+use crate::api;
+use crate::auth::{Authenticated, Scope};
+use crate::error::ApiError;
+use crate::{GenericJsonResponse, ServerData, User};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::options::{FindOptions, IndexOptions};
+use mongodb::{Collection, IndexModel};
+use rocket::http::Status;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, State};
+use serde_json::{json, Value};
+use std::net::IpAddr;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+const PAGE_SIZE: i64 = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    UserRegistered,
+    MovieCreated,
+    MovieUpdated,
+    MovieDeleted,
+    PasswordChanged,
+    UsernameChanged,
+    EmailChanged,
+    AccountDeleted,
+    TokenCreated,
+    TokenRevoked,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetKind {
+    Movie,
+    User,
+    ApiToken,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditTarget {
+    kind: TargetKind,
+    id: ObjectId,
+}
+
+/// One security-relevant or content change. The collection is append-only:
+/// nothing in the server updates or deletes entries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    /// Missing for failed logins with an unknown username.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    actor: Option<ObjectId>,
+    /// The name at the time, since users can rename themselves.
+    actor_name: String,
+    action: AuditAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<AuditTarget>,
+    timestamp: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    /// The fields that changed, as `{field: {before, after}}`. Secrets such
+    /// as passwords are never passed in.
+    #[serde(default, skip_serializing_if = "Document::is_empty")]
+    changes: Document,
+}
+
+impl AuditEntry {
+    pub fn new(action: AuditAction, actor: &User, ip: Option<IpAddr>) -> Self {
+        AuditEntry {
+            actor: actor.id,
+            ..AuditEntry::anonymous(action, &actor.name, ip)
+        }
+    }
+
+    /// An entry for someone who is not a known user, such as a failed login
+    /// with a made-up name.
+    pub fn anonymous(action: AuditAction, actor_name: &str, ip: Option<IpAddr>) -> Self {
+        AuditEntry {
+            id: None,
+            actor: None,
+            actor_name: actor_name.to_string(),
+            action,
+            target: None,
+            timestamp: DateTime::now(),
+            ip: ip.map(|ip| ip.to_string()),
+            changes: Document::new(),
+        }
+    }
+
+    pub fn target(mut self, kind: TargetKind, id: Option<ObjectId>) -> Self {
+        self.target = id.map(|id| AuditTarget { kind, id });
+        self
+    }
+
+    /// Records the fields that differ between `before` and `after`. Pass an
+    /// empty document for the side that does not exist, as on create or delete.
+    pub fn changes(mut self, before: Document, after: Document) -> Self {
+        self.changes = diff(&before, &after);
+        self
+    }
+}
+
+/// `{field: {before, after}}` for every field whose value differs; a field
+/// missing on one side is `null` there.
+fn diff(before: &Document, after: &Document) -> Document {
+    let mut changes = Document::new();
+    let fields = before.keys().chain(after.keys().filter(|key| !before.contains_key(key.as_str())));
+    for field in fields {
+        let old = before.get(field).cloned().unwrap_or(Bson::Null);
+        let new = after.get(field).cloned().unwrap_or(Bson::Null);
+        if old != new {
+            changes.insert(field.clone(), doc! {"before": old, "after": new});
+        }
+    }
+    changes
+}
+
+/// Stores an entry. Failures are logged rather than returned so a struggling
+/// audit collection does not block logins or edits.
+pub async fn record(entry: AuditEntry, server_data: &ServerData) {
+    if let Err(error) = server_data.audit_log.insert_one(&entry, None).await {
+        error!(?error, action = ?entry.action, actor = %entry.actor_name, "Failed to record audit entry");
+    }
+}
+
+/// The admin query filters on actor or action within a time range, newest first.
+pub async fn create_indexes(audit_log: Collection<AuditEntry>) {
+    let indexes = [
+        IndexModel::builder().keys(doc! {"timestamp": -1}).build(),
+        IndexModel::builder().keys(doc! {"actor": 1, "timestamp": -1}).options(IndexOptions::builder().sparse(true).build()).build(),
+        IndexModel::builder().keys(doc! {"actor_name": 1, "timestamp": -1}).build(),
+        IndexModel::builder().keys(doc! {"action": 1, "timestamp": -1}).build(),
+    ];
+    if let Err(error) = audit_log.create_indexes(indexes, None).await {
+        error!(?error, "Failed to create audit log indexes");
+    }
+}
+
+fn parse_time(field: &str, value: &str) -> Result<DateTime, ApiError> {
+    DateTime::parse_rfc3339_str(value)
+        .map_err(|_| ApiError::Other(Status::BadRequest, format!("`{}` must be an RFC 3339 timestamp", field)))
+}
+
+/// `actor` is a user id or, failing that, a username as it was when the
+/// entry was written. `since` is inclusive, `until` exclusive.
+fn query_filter(actor: Option<&str>, action: Option<&str>, since: Option<&str>, until: Option<&str>) -> Result<Document, ApiError> {
+    let mut filter = Document::new();
+    if let Some(actor) = actor {
+        match ObjectId::parse_str(actor) {
+            Ok(id) => filter.insert("actor", id),
+            Err(_) => filter.insert("actor_name", actor),
+        };
+    }
+    if let Some(action) = action {
+        let action: AuditAction = serde_json::from_value(json!(action))
+            .map_err(|_| ApiError::Other(Status::BadRequest, format!("Unknown audit action `{}`", action)))?;
+        filter.insert("action", mongodb::bson::to_bson(&action).unwrap_or_default());
+    }
+    let mut timestamp = Document::new();
+    if let Some(since) = since {
+        timestamp.insert("$gte", parse_time("since", since)?);
+    }
+    if let Some(until) = until {
+        timestamp.insert("$lt", parse_time("until", until)?);
+    }
+    if !timestamp.is_empty() {
+        filter.insert("timestamp", timestamp);
+    }
+    Ok(filter)
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+struct AuditEntryResponse {
+    id: String,
+    actor: Option<String>,
+    actor_name: String,
+    action: AuditAction,
+    target: Option<Value>,
+    timestamp: String,
+    ip: Option<String>,
+    changes: Value,
+}
+
+impl From<AuditEntry> for AuditEntryResponse {
+    fn from(entry: AuditEntry) -> Self {
+        AuditEntryResponse {
+            id: entry.id.map(|id| id.to_hex()).unwrap_or_default(),
+            actor: entry.actor.map(|id| id.to_hex()),
+            actor_name: entry.actor_name,
+            action: entry.action,
+            target: entry.target.map(|target| json!({"kind": target.kind, "id": target.id.to_hex()})),
+            timestamp: entry.timestamp.try_to_rfc3339_string().unwrap_or_default(),
+            ip: entry.ip,
+            changes: Bson::Document(entry.changes).into_relaxed_extjson(),
+        }
+    }
+}
+
+/// Reads the audit log, newest first. Only for users flagged as `admin` in
+/// the database, and API tokens need the `admin` scope.
+#[utoipa::path(
+    tag = "admin",
+    responses(
+        (status = 200, description = "One page of entries"),
+        (status = 400, description = "An unknown action, a malformed time or a page out of range", body = crate::api::Problem),
+        (status = 403, description = "The caller is not an administrator", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[get("/admin/audit?<actor>&<action>&<since>&<until>&<page>")]
+pub async fn get_audit_log(
+    actor: Option<&str>,
+    action: Option<&str>,
+    since: Option<&str>,
+    until: Option<&str>,
+    page: Option<u64>,
+    auth: Authenticated,
+    server_data: &State<ServerData>,
+) -> Result<GenericJsonResponse, ApiError> {
+    if !auth.user.admin || auth.scope < Scope::Admin {
+        return Err(ApiError::Forbidden("Only administrators can read the audit log".to_string()));
+    }
+    let filter = query_filter(actor, action, since, until)?;
+    let page = page.unwrap_or(0);
+    let options = FindOptions::builder()
+        .sort(doc! {"timestamp": -1})
+        .skip(api::page_offset(page, PAGE_SIZE)?)
+        .limit(PAGE_SIZE)
+        .build();
+    let mut cursor = server_data.audit_log.find(filter, options).await?;
+    let mut entries = Vec::new();
+    while cursor.advance().await? {
+        match cursor.deserialize_current() {
+            Ok(entry) => entries.push(AuditEntryResponse::from(entry)),
+            Err(error) => warn!(?error, "Skipping malformed audit entry"),
+        }
+    }
+    Ok(GenericJsonResponse::ok(&json!({"page": page, "entries": entries})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, query_filter};
+    use mongodb::bson::{doc, Bson, DateTime};
+
+    #[test]
+    fn test_diff() {
+        let before = doc! {"title": "Alien", "author": "Scott", "image_url": "a.png"};
+        let after = doc! {"title": "Aliens", "author": "Scott", "year": 1986};
+        assert_eq!(
+            diff(&before, &after),
+            doc! {
+                "title": {"before": "Alien", "after": "Aliens"},
+                "image_url": {"before": "a.png", "after": Bson::Null},
+                "year": {"before": Bson::Null, "after": 1986},
+            }
+        );
+        assert!(diff(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn test_query_filter() {
+        let filter = query_filter(Some("alice"), Some("login_failed"), Some("2024-01-01T00:00:00Z"), None).unwrap();
+        let since = DateTime::parse_rfc3339_str("2024-01-01T00:00:00Z").unwrap();
+        assert_eq!(filter, doc! {"actor_name": "alice", "action": "login_failed", "timestamp": {"$gte": since}});
+
+        let filter = query_filter(Some("65a1f0c2e4b0a1b2c3d4e5f6"), None, None, None).unwrap();
+        assert!(filter.get_object_id("actor").is_ok());
+
+        assert!(query_filter(None, Some("drop_tables"), None, None).is_err());
+        assert!(query_filter(None, None, None, Some("yesterday")).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use crate::jwt::Jwt;
+use crate::tokens::TOKEN_PREFIX;
+use crate::{GenericJsonResponse, ServerData, User};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use rocket::http::{CookieJar, Method, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{catch, State};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use tracing::{error, Span};
+use utoipa::ToSchema;
+
+/// What an API token may do. Ordered so a higher scope includes the lower ones.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// `GET` requests.
+    Read,
+    /// Creating and changing content such as movies, lists and follows.
+    Write,
+    /// Account-level actions: API tokens, password, username, deleting the account.
+    Admin,
+}
+
+impl Scope {
+    fn required_for(method: Method) -> Scope {
+        match method {
+            Method::Get | Method::Head | Method::Options => Scope::Read,
+            _ => Scope::Write,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+/// SHA-256 of an API token, which is all that is stored. Tokens are long
+/// random strings, so a fast unsalted hash is enough.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// The message of the last guard that failed, shown by the 401/403 catchers.
+#[derive(Default)]
+struct GuardError(Mutex<Option<String>>);
+
+pub fn reject<T>(request: &Request<'_>, status: Status, message: &str) -> Outcome<T, String> {
+    let error = request.local_cache(GuardError::default);
+    *error.0.lock().unwrap_or_else(|error| error.into_inner()) = Some(message.to_string());
+    Outcome::Error((status, message.to_string()))
+}
+
+/// The message of the guard that failed the request, if one did.
+pub fn guard_error(request: &Request<'_>) -> Option<String> {
+    let error = request.local_cache(GuardError::default);
+    let message = error.0.lock().unwrap_or_else(|error| error.into_inner()).clone();
+    message
+}
+
+fn guard_error_response(request: &Request<'_>, status: Status, default: &str) -> GenericJsonResponse {
+    let message = guard_error(request);
+    GenericJsonResponse::error(status, message.as_deref().unwrap_or(default))
+}
+
+#[catch(401)]
+pub fn unauthorized(request: &Request<'_>) -> GenericJsonResponse {
+    guard_error_response(request, Status::Unauthorized, "Unauthorized")
+}
+
+#[catch(403)]
+pub fn forbidden(request: &Request<'_>) -> GenericJsonResponse {
+    guard_error_response(request, Status::Forbidden, "Forbidden")
+}
+
+/// Looks up the user identified by the `username` and `id` cookies.
+pub async fn session_user(cookies: &CookieJar<'_>, server_data: &ServerData) -> Result<User, GenericJsonResponse> {
+    let (Some(username), Some(uuid)) = (cookies.get("username"), cookies.get("id")) else {
+        return Err(GenericJsonResponse::error(Status::Unauthorized, "Unauthorized"));
+    };
+    let user_doc = doc! {"name": username.value(), "uuid": uuid.value()};
+    match server_data.users.find_one(user_doc, None).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(GenericJsonResponse::error(Status::Unauthorized, "Unauthorized")),
+        Err(error) => {
+            error!(?error, "Database error");
+            Err(GenericJsonResponse::error(Status::InternalServerError, "Database error"))
+        }
+    }
+}
+
+/// The caller, authenticated by an `Authorization: Bearer` API token, or by
+/// what `login` handed out: session cookies, or an access JWT in JWT mode.
+/// API token requests are also checked against the token's scope: `read`
+/// for `GET`, `write` for anything else.
+pub struct Authenticated {
+    pub user: User,
+    pub scope: Scope,
+}
+
+impl Authenticated {
+    /// Fails with 403 unless the caller's scope covers `scope`. Sessions
+    /// always have every scope.
+    pub fn require(&self, scope: Scope) -> Result<(), GenericJsonResponse> {
+        if self.scope < scope {
+            return Err(GenericJsonResponse::error(Status::Forbidden, &missing_scope(scope)));
+        }
+        Ok(())
+    }
+}
+
+fn missing_scope(scope: Scope) -> String {
+    format!("This API token lacks the `{}` scope", scope.as_str())
+}
+
+async fn token_user(request: &Request<'_>, token: &str, server_data: &ServerData) -> Outcome<Authenticated, String> {
+    let filter = doc! {"token_hash": hash_token(token), "revoked_at": null};
+    let token = match server_data.api_tokens.find_one(filter, None).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return reject(request, Status::Unauthorized, "Invalid or revoked API token"),
+        Err(error) => {
+            error!(?error, "Database error");
+            return reject(request, Status::InternalServerError, "Database error");
+        }
+    };
+    let user = match server_data.users.find_one(doc! {"_id": token.user_id}, None).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return reject(request, Status::Unauthorized, "Invalid or revoked API token"),
+        Err(error) => {
+            error!(?error, "Database error");
+            return reject(request, Status::InternalServerError, "Database error");
+        }
+    };
+
+    let update = doc! {"$set": {"last_used_at": DateTime::now()}};
+    if let Err(error) = server_data.api_tokens.update_one(doc! {"_id": token.id}, update, None).await {
+        error!(?error, "Failed to record API token use");
+    }
+    let required = Scope::required_for(request.method());
+    if token.scope < required {
+        return reject(request, Status::Forbidden, &missing_scope(required));
+    }
+    Outcome::Success(Authenticated { user, scope: token.scope })
+}
+
+/// Only the signature is needed to trust an access token; the user is loaded
+/// because handlers work with the full record.
+async fn jwt_user(request: &Request<'_>, token: &str, jwt: &Jwt, server_data: &ServerData) -> Outcome<Authenticated, String> {
+    let claims = match jwt.verify(token) {
+        Ok(claims) => claims,
+        Err(message) => return reject(request, Status::Unauthorized, message),
+    };
+    let Ok(user_id) = ObjectId::parse_str(&claims.sub) else {
+        return reject(request, Status::Unauthorized, "Invalid access token");
+    };
+    match server_data.users.find_one(doc! {"_id": user_id}, None).await {
+        Ok(Some(user)) => Outcome::Success(Authenticated { user, scope: Scope::Admin }),
+        Ok(None) => reject(request, Status::Unauthorized, "Invalid access token"),
+        Err(error) => {
+            error!(?error, "Database error");
+            reject(request, Status::InternalServerError, "Database error")
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authenticated {
+    type Error = String;
+
+    /// Also names the user on the request's log span.
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let outcome = authenticate(request).await;
+        if let Outcome::Success(auth) = &outcome {
+            Span::current().record("user", auth.user.name.as_str());
+        }
+        outcome
+    }
+}
+
+/// Finds the user by bearer token, or by session cookies if there is none.
+async fn authenticate(request: &Request<'_>) -> Outcome<Authenticated, String> {
+    let server_data = match request.guard::<&State<ServerData>>().await {
+        Outcome::Success(server_data) => server_data,
+        _ => return reject(request, Status::InternalServerError, "Server state unavailable"),
+    };
+    let jwt = request.rocket().state::<Jwt>().filter(|jwt| jwt.enabled());
+    if let Some(header) = request.headers().get_one("Authorization") {
+        let Some(token) = header.strip_prefix("Bearer ").map(str::trim) else {
+            return reject(request, Status::Unauthorized, "Authorization header must use the Bearer scheme");
+        };
+        return match jwt {
+            Some(jwt) if !token.starts_with(TOKEN_PREFIX) => jwt_user(request, token, jwt, server_data).await,
+            _ => token_user(request, token, server_data).await,
+        };
+    }
+    if jwt.is_some() {
+        return reject(request, Status::Unauthorized, "Unauthorized");
+    }
+    match session_user(request.cookies(), server_data).await {
+        Ok(user) => Outcome::Success(Authenticated { user, scope: Scope::Admin }),
+        Err(response) if response.status == Status::Unauthorized => reject(request, Status::Unauthorized, "Unauthorized"),
+        Err(response) => reject(request, response.status, "Database error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_token, Scope};
+    use rocket::http::Method;
+
+    #[test]
+    fn test_scopes() {
+        assert!(Scope::Read < Scope::Write && Scope::Write < Scope::Admin);
+        assert_eq!(Scope::required_for(Method::Get), Scope::Read);
+        assert_eq!(Scope::required_for(Method::Delete), Scope::Write);
+    }
+
+    #[test]
+    fn test_hash_token() {
+        assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
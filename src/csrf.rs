@@ -0,0 +1,87 @@
+use crate::auth::reject;
+use cookie::time::{Duration, OffsetDateTime};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Cookie, SameSite, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::Response;
+use uuid::Uuid;
+
+pub const COOKIE_NAME: &str = "csrf_token";
+pub const HEADER_NAME: &str = "X-CSRF-Token";
+
+/// Double-submit CSRF token. It is readable by our own scripts, which echo
+/// it back in the `X-CSRF-Token` header; other sites can neither read the
+/// cookie nor set the header, so a forged request fails the comparison.
+pub fn token_cookie() -> Cookie<'static> {
+    let mut cookie = Cookie::new(COOKIE_NAME, Uuid::new_v4().simple().to_string());
+    cookie.set_path("/");
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_expires(OffsetDateTime::now_utc() + Duration::weeks(52));
+    cookie
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Request guard for every route that changes state. Succeeds only when the
+/// `X-CSRF-Token` header matches the `csrf_token` cookie, or when the request
+/// carries an `Authorization` header, which browsers never attach on their own.
+pub struct CsrfVerified;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfVerified {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if request.headers().contains("Authorization") {
+            return Outcome::Success(CsrfVerified);
+        }
+        let cookie = request.cookies().get(COOKIE_NAME).map(|cookie| cookie.value().to_string());
+        let header = request.headers().get_one(HEADER_NAME);
+        match (cookie, header) {
+            (Some(cookie), Some(header)) if !cookie.is_empty() && constant_time_eq(&cookie, header) => {
+                Outcome::Success(CsrfVerified)
+            }
+            _ => reject(request, Status::Forbidden, "Missing or invalid CSRF token"),
+        }
+    }
+}
+
+/// Hands a token to visitors that do not have one yet, so forms work before
+/// the user has logged in.
+pub struct CsrfFairing;
+
+#[rocket::async_trait]
+impl Fairing for CsrfFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF token issuer",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if request.cookies().get(COOKIE_NAME).is_some() {
+            return;
+        }
+        let prefix = format!("{}=", COOKIE_NAME);
+        if response.headers().get("Set-Cookie").any(|cookie| cookie.starts_with(&prefix)) {
+            return;
+        }
+        response.adjoin_header(token_cookie());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq("abc", "abc"));
+        assert!(!constant_time_eq("abc", "abd"));
+        assert!(!constant_time_eq("abc", "ab"));
+        assert!(!constant_time_eq("", "a"));
+    }
+}
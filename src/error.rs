@@ -0,0 +1,255 @@
+use crate::auth;
+use crate::validation::FieldErrors;
+use crate::GenericJsonResponse;
+use rocket::catch;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::time::Duration;
+use tracing::error;
+
+/// Why a request failed. Responds with an RFC 7807 `application/problem+json`
+/// body whose `code` is stable, so clients can tell errors apart without
+/// matching on the message.
+#[derive(Debug)]
+pub enum ApiError {
+    /// Request fields failed validation; the messages are under `fields`.
+    Validation(FieldErrors),
+    InvalidCredentials,
+    Forbidden(String),
+    NotFound(String),
+    UsernameTaken,
+    EmailTaken,
+    MovieExists,
+    /// Too many attempts; the client should wait this long.
+    RateLimited(Duration),
+    /// Logged, but never shown to the client.
+    Database(mongodb::error::Error),
+    /// Blob storage failed. Logged, but never shown to the client.
+    Storage(azure_storage::Error),
+    /// Logged, but never shown to the client.
+    Internal(String),
+    /// Anything without a variant of its own. The code is derived from the status.
+    Other(Status, String),
+}
+
+/// `Not Found` becomes `not_found`.
+fn status_code(status: Status) -> Cow<'static, str> {
+    match status.reason() {
+        Some(reason) => reason.to_ascii_lowercase().replace([' ', '-'], "_").into(),
+        None => "error".into(),
+    }
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::Validation(_) => Status::UnprocessableEntity,
+            ApiError::InvalidCredentials => Status::Unauthorized,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::UsernameTaken | ApiError::EmailTaken | ApiError::MovieExists => Status::Conflict,
+            ApiError::RateLimited(_) => Status::TooManyRequests,
+            ApiError::Database(_) | ApiError::Internal(_) => Status::InternalServerError,
+            ApiError::Storage(_) => Status::BadGateway,
+            ApiError::Other(status, _) => *status,
+        }
+    }
+
+    pub fn code(&self) -> Cow<'static, str> {
+        match self {
+            ApiError::Validation(_) => "validation_failed".into(),
+            ApiError::InvalidCredentials => "invalid_credentials".into(),
+            ApiError::UsernameTaken => "username_taken".into(),
+            ApiError::EmailTaken => "email_taken".into(),
+            ApiError::MovieExists => "movie_exists".into(),
+            ApiError::Database(_) => "database_error".into(),
+            ApiError::Storage(_) => "storage_error".into(),
+            ApiError::Internal(_) => "internal_error".into(),
+            _ => status_code(self.status()),
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            ApiError::Validation(errors) => errors.first().unwrap_or("Invalid request").to_string(),
+            ApiError::InvalidCredentials => "Wrong username or password".to_string(),
+            ApiError::UsernameTaken => "Username already exists".to_string(),
+            ApiError::EmailTaken => "Email is already in use".to_string(),
+            ApiError::MovieExists => "Movie already exists".to_string(),
+            ApiError::RateLimited(_) => "Too many attempts, try again later".to_string(),
+            ApiError::Database(_) => "Database error".to_string(),
+            ApiError::Storage(_) => "Storage error".to_string(),
+            ApiError::Internal(_) => "Internal server error".to_string(),
+            ApiError::Forbidden(detail) | ApiError::NotFound(detail) | ApiError::Other(_, detail) => detail.clone(),
+        }
+    }
+
+    /// Whole seconds to wait, rounded up.
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            ApiError::RateLimited(wait) => Some(wait.as_secs() + u64::from(wait.subsec_nanos() > 0)),
+            _ => None,
+        }
+    }
+
+    /// The problem document. `error` repeats `detail` for clients written
+    /// before this format.
+    pub fn body(&self) -> Value {
+        let status = self.status();
+        let detail = self.detail();
+        let mut body = json!({
+            "type": "about:blank",
+            "title": status.reason().unwrap_or("Error"),
+            "status": status.code,
+            "detail": detail,
+            "code": self.code(),
+            "error": detail,
+        });
+        let fields = match self {
+            ApiError::Validation(errors) => Some(json!(errors)),
+            ApiError::UsernameTaken => Some(json!({"name": [self.detail()]})),
+            ApiError::EmailTaken => Some(json!({"email": [self.detail()]})),
+            _ => None,
+        };
+        if let Some(fields) = fields {
+            body["fields"] = fields;
+        }
+        if let Some(retry_after) = self.retry_after() {
+            body["retry_after"] = json!(retry_after);
+        }
+        body
+    }
+
+    /// Logs the causes that are kept from the client.
+    pub fn log(&self) {
+        match self {
+            ApiError::Database(cause) => error!(error = ?cause, "Database error"),
+            ApiError::Storage(cause) => error!(error = ?cause, "Storage error"),
+            ApiError::Internal(cause) => error!(error = %cause, "Internal error"),
+            _ => (),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for ApiError {
+    fn from(error: mongodb::error::Error) -> Self {
+        ApiError::Database(error)
+    }
+}
+
+impl From<FieldErrors> for ApiError {
+    fn from(errors: FieldErrors) -> Self {
+        ApiError::Validation(errors)
+    }
+}
+
+impl From<ApiError> for GenericJsonResponse {
+    fn from(error: ApiError) -> Self {
+        error.log();
+        GenericJsonResponse {
+            json: error.body().to_string(),
+            status: error.status(),
+            cookies: Vec::new(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let retry_after = self.retry_after();
+        let mut response = GenericJsonResponse::from(self).respond_to(request)?;
+        if let Some(retry_after) = retry_after {
+            response.set_raw_header("Retry-After", retry_after.to_string());
+        }
+        Ok(response)
+    }
+}
+
+/// Whether blob storage answered 404.
+pub fn is_not_found(error: &azure_storage::Error) -> bool {
+    matches!(error.kind(), azure_storage::ErrorKind::HttpResponse { status, .. } if *status as u16 == 404)
+}
+
+/// `application/problem+json`, the media type of every error body.
+pub fn problem_content_type() -> ContentType {
+    ContentType::new("application", "problem+json")
+}
+
+#[catch(400)]
+pub fn bad_request() -> ApiError {
+    ApiError::Other(Status::BadRequest, "The request could not be read".to_string())
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request<'_>) -> ApiError {
+    ApiError::NotFound(format!("Nothing found at {}", request.uri().path()))
+}
+
+/// Rocket answers 422 when a body is well-formed but does not have the
+/// expected fields.
+#[catch(422)]
+pub fn unprocessable_entity() -> ApiError {
+    ApiError::Other(Status::UnprocessableEntity, "The request body does not have the expected fields".to_string())
+}
+
+/// Reached when a guard fails with a 500, such as when the database is down,
+/// or when a handler panics. Only the latter has the code
+/// `internal_server_error`.
+#[catch(500)]
+pub fn internal_error(request: &Request<'_>) -> ApiError {
+    match auth::guard_error(request) {
+        Some(message) => ApiError::Internal(message),
+        None => ApiError::Other(Status::InternalServerError, "Internal server error".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{status_code, ApiError};
+    use crate::validation::FieldErrors;
+    use rocket::http::Status;
+    use std::time::Duration;
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(status_code(Status::NotFound), "not_found");
+        assert_eq!(status_code(Status::UnprocessableEntity), "unprocessable_entity");
+        assert_eq!(status_code(Status::new(599)), "error");
+    }
+
+    #[test]
+    fn test_problem_body() {
+        let body = ApiError::NotFound("Movie not found".to_string()).body();
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Not Found");
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["detail"], "Movie not found");
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["error"], "Movie not found");
+
+        let mut errors = FieldErrors::default();
+        errors.add("name", vec!["Username is too short".to_string()]);
+        let body = ApiError::Validation(errors).body();
+        assert_eq!(body["status"], 422);
+        assert_eq!(body["code"], "validation_failed");
+        assert_eq!(body["detail"], "Username is too short");
+        assert_eq!(body["fields"]["name"][0], "Username is too short");
+
+        let body = ApiError::UsernameTaken.body();
+        assert_eq!((body["status"].as_u64(), body["code"].as_str()), (Some(409), Some("username_taken")));
+        assert!(body["fields"]["name"].is_array());
+
+        let body = ApiError::RateLimited(Duration::from_millis(1500)).body();
+        assert_eq!(body["retry_after"], 2);
+    }
+
+    #[test]
+    fn test_internal_details_hidden() {
+        let body = ApiError::Internal("connection refused at 10.0.0.1".to_string()).body();
+        assert_eq!(body["status"], 500);
+        assert_eq!(body["detail"], "Internal server error");
+    }
+}
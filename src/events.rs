@@ -0,0 +1,147 @@
+use crate::error::ApiError;
+use crate::{MovieResponse, ServerData};
+use mongodb::bson::oid::ObjectId;
+use rocket::http::Status;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::tokio::time::Duration;
+use rocket::{get, Shutdown, State};
+use serde_json::json;
+
+/// Events a subscriber can fall behind by before it misses some.
+const CAPACITY: usize = 256;
+/// How often an idle stream sends a comment, so proxies keep it open.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// A change to a movie that open `/movies` pages should show.
+#[derive(Debug, Clone)]
+pub enum MovieEvent {
+    Added(MovieResponse),
+    Deleted(ObjectId),
+    RatingChanged { movie_id: ObjectId, avg_rating: f32, num_ratings: u32 },
+}
+
+impl MovieEvent {
+    fn movie_id(&self) -> Option<ObjectId> {
+        match self {
+            MovieEvent::Added(movie) => ObjectId::parse_str(&movie.id).ok(),
+            MovieEvent::Deleted(movie_id) | MovieEvent::RatingChanged { movie_id, .. } => Some(*movie_id),
+        }
+    }
+
+    fn to_sse(&self) -> Event {
+        match self {
+            MovieEvent::Added(movie) => Event::json(movie).event("movie-added"),
+            MovieEvent::Deleted(movie_id) => Event::json(&json!({"id": movie_id.to_hex()})).event("movie-deleted"),
+            MovieEvent::RatingChanged { movie_id, avg_rating, num_ratings } => {
+                Event::json(&json!({"id": movie_id.to_hex(), "avg_rating": avg_rating, "num_ratings": num_ratings})).event("rating-changed")
+            }
+        }
+    }
+}
+
+/// Hands movie changes from the handlers that make them to every open event
+/// stream on this instance.
+#[derive(Clone)]
+pub struct EventBus(broadcast::Sender<MovieEvent>);
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus(broadcast::channel(CAPACITY).0)
+    }
+
+    /// Does nothing when nobody is listening.
+    pub fn publish(&self, event: MovieEvent) {
+        let _ = self.0.send(event);
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<MovieEvent> {
+        self.0.subscribe()
+    }
+}
+
+/// Which events a stream passes on. No movies means every event.
+struct Filter(Vec<ObjectId>);
+
+impl Filter {
+    fn parse(movies: &[&str]) -> Result<Self, ApiError> {
+        movies
+            .iter()
+            .map(|id| ObjectId::parse_str(id).map_err(|_| ApiError::Other(Status::BadRequest, format!("`{}` is not a valid movie id", id))))
+            .collect::<Result<_, _>>()
+            .map(Filter)
+    }
+
+    /// A filtered stream never sees `movie-added`, as new movies cannot be
+    /// among the ones asked for.
+    fn matches(&self, event: &MovieEvent) -> bool {
+        self.0.is_empty() || event.movie_id().is_some_and(|id| self.0.contains(&id))
+    }
+}
+
+/// Server-sent events for movies being added and deleted and ratings
+/// changing, limited to the given movies if any are. A client that falls
+/// behind gets a `lagged` event with how many it missed and should reload.
+#[utoipa::path(
+    tag = "movies",
+    params(("movie" = Option<Vec<String>>, Query, description = "Only events for these movie ids")),
+    responses(
+        (status = 200, description = "`movie-added`, `movie-deleted` and `rating-changed` events", content_type = "text/event-stream"),
+        (status = 400, description = "A malformed movie id", body = crate::api::Problem),
+    )
+)]
+#[get("/events?<movie>")]
+pub fn movie_events(movie: Vec<&str>, server_data: &State<ServerData>, mut shutdown: Shutdown) -> Result<EventStream![], ApiError> {
+    let filter = Filter::parse(&movie)?;
+    let mut events = server_data.events.subscribe();
+    let stream = EventStream! {
+        loop {
+            let event = select! {
+                event = events.recv() => event,
+                _ = &mut shutdown => break,
+            };
+            match event {
+                Ok(event) if filter.matches(&event) => yield event.to_sse(),
+                Ok(_) => (),
+                Err(RecvError::Lagged(missed)) => yield Event::json(&json!({"missed": missed})).event("lagged"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    Ok(stream.heartbeat(HEARTBEAT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventBus, Filter, MovieEvent};
+    use crate::MovieResponse;
+    use mongodb::bson::oid::ObjectId;
+    use rocket::async_test;
+
+    #[test]
+    fn test_filter() {
+        let (watched, other) = (ObjectId::new(), ObjectId::new());
+        let changed = |movie_id| MovieEvent::RatingChanged { movie_id, avg_rating: 4.0, num_ratings: 2 };
+        let added = MovieEvent::Added(MovieResponse { id: other.to_hex(), ..Default::default() });
+
+        let everything = Filter::parse(&[]).unwrap();
+        assert!(everything.matches(&changed(other)) && everything.matches(&added));
+
+        let filter = Filter::parse(&[&watched.to_hex()]).unwrap();
+        assert!(filter.matches(&changed(watched)) && filter.matches(&MovieEvent::Deleted(watched)));
+        assert!(!filter.matches(&changed(other)) && !filter.matches(&added));
+
+        assert!(Filter::parse(&["not-an-id"]).is_err());
+    }
+
+    #[async_test]
+    async fn test_bus() {
+        let bus = EventBus::new();
+        bus.publish(MovieEvent::Deleted(ObjectId::new()));
+        let mut events = bus.subscribe();
+        let movie_id = ObjectId::new();
+        bus.publish(MovieEvent::Deleted(movie_id));
+        assert!(matches!(events.recv().await, Ok(MovieEvent::Deleted(id)) if id == movie_id));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::api;
+use crate::auth::Authenticated;
+use crate::csrf::CsrfVerified;
+use crate::telemetry;
+use crate::{database_error, movies_in_order, user_by_name, GenericJsonResponse, MovieResponse, ServerData, User};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::FindOptions;
+use mongodb::{Collection, IndexModel};
+use rocket::http::Status;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, get, post, State};
+use serde_json::json;
+use std::collections::HashMap;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+const PAGE_SIZE: i64 = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityKind {
+    MovieAdded,
+    MovieRated,
+    ReviewPosted,
+}
+
+/// Something a user did that shows up in their followers' feeds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Activity {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    pub actor: ObjectId,
+    actor_name: String,
+    kind: ActivityKind,
+    pub movie_id: ObjectId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub review: Option<String>,
+    pub created_at: DateTime,
+}
+
+impl Activity {
+    pub fn new(actor: &User, kind: ActivityKind, movie_id: ObjectId) -> Option<Self> {
+        Some(Activity {
+            id: None,
+            actor: actor.id?,
+            actor_name: actor.name.clone(),
+            kind,
+            movie_id,
+            rating: None,
+            review: None,
+            created_at: DateTime::now(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct ActivityResponse {
+    actor: String,
+    kind: ActivityKind,
+    movie: Option<MovieResponse>,
+    rating: Option<f64>,
+    review: Option<String>,
+    created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct FeedResponse {
+    page: u64,
+    events: Vec<ActivityResponse>,
+}
+
+/// Stores an activity event and reports it to telemetry. Failures are logged rather than returned since
+/// the feed is secondary to the action that produced the event.
+pub async fn record_activity(activity: Option<Activity>, server_data: &ServerData) {
+    let Some(activity) = activity else {
+        return;
+    };
+    let event = match activity.kind {
+        ActivityKind::MovieAdded => Some(telemetry::Event::MovieAdded),
+        ActivityKind::MovieRated => Some(telemetry::Event::RatingSubmitted),
+        ActivityKind::ReviewPosted => None,
+    };
+    if let Some(event) = event {
+        let movie_id = activity.movie_id.to_hex();
+        server_data.telemetry.track_event(event, &[("username", &activity.actor_name), ("movie_id", &movie_id)]);
+    }
+    if let Err(error) = server_data.activities.insert_one(activity, None).await {
+        error!(?error, "Failed to record activity");
+    }
+}
+
+/// The feed reads one page of the followed users' activities, newest first.
+pub async fn create_indexes(activities: Collection<Activity>) {
+    let index = IndexModel::builder().keys(doc! {"actor": 1, "created_at": -1}).build();
+    if let Err(error) = activities.create_index(index, None).await {
+        error!(?error, "Failed to create activity indexes");
+    }
+}
+
+/// Which of `users` hide their ratings, whose ratings and reviews then stay
+/// out of feeds as they do out of profiles.
+async fn hiding_ratings(users: &[ObjectId], server_data: &ServerData) -> mongodb::error::Result<Vec<ObjectId>> {
+    let filter = doc! {"_id": {"$in": users}, "privacy.hide_ratings": true};
+    let mut cursor = server_data.users.find(filter, None).await?;
+    let mut hiding = Vec::new();
+    while cursor.advance().await? {
+        if let Ok(user) = cursor.deserialize_current() {
+            hiding.extend(user.id);
+        }
+    }
+    Ok(hiding)
+}
+
+async fn find_user_by_name(name: &str, server_data: &ServerData) -> Result<User, GenericJsonResponse> {
+    match user_by_name(name, &server_data.users).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(GenericJsonResponse::error(Status::NotFound, "User not found")),
+        Err(error) => Err(database_error(error)),
+    }
+}
+
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 200, description = "Following"),
+        (status = 404, description = "No such user", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[post("/users/<name>/follow")]
+pub async fn follow_user(name: &str, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let followee = match find_user_by_name(name, server_data).await {
+        Ok(followee) => followee,
+        Err(response) => return response,
+    };
+    if followee.id == user.id {
+        return GenericJsonResponse::error(Status::UnprocessableEntity, "You cannot follow yourself");
+    }
+
+    let update = doc! {"$addToSet": {"following": followee.id}};
+    match server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        Ok(_) => GenericJsonResponse::ok(&json!({"message": format!("Following {}", followee.name)})),
+        Err(error) => database_error(error),
+    }
+}
+
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 200, description = "Not following any more"),
+        (status = 404, description = "No such user", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[delete("/users/<name>/follow")]
+pub async fn unfollow_user(name: &str, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let followee = match find_user_by_name(name, server_data).await {
+        Ok(followee) => followee,
+        Err(response) => return response,
+    };
+
+    let update = doc! {"$pull": {"following": followee.id}};
+    match server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        Ok(_) => GenericJsonResponse::ok(&json!({"message": format!("Unfollowed {}", followee.name)})),
+        Err(error) => database_error(error),
+    }
+}
+
+/// What the users the caller follows have done, newest first. Ratings and
+/// reviews of users who hide their ratings are left out.
+#[utoipa::path(
+    tag = "feed",
+    responses(
+        (status = 200, description = "One page of events", body = FeedResponse),
+        (status = 400, description = "A page out of range", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[get("/feed?<page>")]
+pub async fn get_feed(page: Option<u64>, auth: Authenticated, server_data: &State<ServerData>) -> GenericJsonResponse {
+    let user = auth.user;
+    let page = page.unwrap_or(0);
+    let skip = match api::page_offset(page, PAGE_SIZE) {
+        Ok(skip) => skip,
+        Err(error) => return error.into(),
+    };
+    if user.following.is_empty() {
+        return GenericJsonResponse::ok(&FeedResponse { page, events: Vec::new() });
+    }
+
+    let hiding = match hiding_ratings(&user.following, server_data).await {
+        Ok(hiding) => hiding,
+        Err(error) => return database_error(error),
+    };
+    let filter = doc! {
+        "actor": {"$in": &user.following},
+        "$nor": [{"actor": {"$in": hiding}, "kind": {"$ne": "movie_added"}}],
+    };
+    let options = FindOptions::builder()
+        .sort(doc! {"created_at": -1, "_id": -1})
+        .skip(skip)
+        .limit(PAGE_SIZE)
+        .build();
+    let mut activities: Vec<Activity> = Vec::new();
+    let mut cursor = match server_data.activities.find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(error) => return database_error(error),
+    };
+    loop {
+        match cursor.advance().await {
+            Ok(true) => match cursor.deserialize_current() {
+                Ok(activity) => activities.push(activity),
+                Err(error) => warn!(?error, "Skipping malformed activity"),
+            },
+            Ok(false) => break,
+            Err(error) => return database_error(error),
+        }
+    }
+
+    let mut movie_ids: Vec<ObjectId> = activities.iter().map(|activity| activity.movie_id).collect();
+    movie_ids.sort();
+    movie_ids.dedup();
+    let movies: HashMap<String, MovieResponse> = match movies_in_order(&movie_ids, server_data).await {
+        Ok(movies) => movies.into_iter().map(|movie| (movie.id.clone(), movie)).collect(),
+        Err(response) => return response,
+    };
+
+    let events = activities
+        .into_iter()
+        .map(|activity| ActivityResponse {
+            movie: movies.get(&activity.movie_id.to_string()).cloned(),
+            actor: activity.actor_name,
+            kind: activity.kind,
+            rating: activity.rating,
+            review: activity.review,
+            created_at: activity.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        })
+        .collect();
+    GenericJsonResponse::ok(&FeedResponse { page, events })
+}
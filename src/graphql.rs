@@ -0,0 +1,416 @@
+use crate::auth::Authenticated;
+use crate::error::ApiError;
+use crate::feed::Activity;
+use crate::profiles::join_date;
+use crate::{collect_movies, user_by_name, GenericJsonResponse, Movie, MovieResponse, ServerData, User};
+use async_graphql::connection::{self, Connection, Edge};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Error, ErrorExtensions, Object, Schema, Variables, ID};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+use rocket::http::Status;
+use rocket::response::content::RawHtml;
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::{get, post, FromForm, State};
+use std::collections::HashMap;
+use tracing::warn;
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+const DEFAULT_REVIEWS: usize = 3;
+/// Reviews loaded per movie; `Movie.reviews` can ask for fewer.
+const MAX_REVIEWS: usize = 20;
+/// Deep enough for GraphiQL's introspection query, but stops
+/// `creator { createdMovies { creator { ... } } }` from going on forever.
+const MAX_DEPTH: usize = 12;
+const MAX_COMPLEXITY: usize = 500;
+
+pub type CinemaSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn schema() -> CinemaSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// The user the request authenticated as, if any.
+struct Caller(Option<User>);
+
+/// Logged like a REST error, with the same `code` as an extension.
+fn graphql_error(error: ApiError) -> Error {
+    error.log();
+    let detail = error.body()["detail"].as_str().unwrap_or("Error").to_string();
+    Error::new(detail).extend_with(|_, extensions| extensions.set("code", error.code().as_ref()))
+}
+
+fn database_error(error: mongodb::error::Error) -> Error {
+    graphql_error(ApiError::Database(error))
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| graphql_error(ApiError::Other(Status::BadRequest, format!("`{}` is not a valid id", id))))
+}
+
+struct MovieLoader(Collection<Movie>);
+
+impl Loader<ObjectId> for MovieLoader {
+    type Value = MovieResponse;
+    type Error = Error;
+
+    async fn load(&self, ids: &[ObjectId]) -> Result<HashMap<ObjectId, MovieResponse>, Error> {
+        let cursor = self.0.find(doc! {"_id": {"$in": ids}}, None).await.map_err(database_error)?;
+        let movies = collect_movies(cursor).await.map_err(graphql_error)?;
+        Ok(movies
+            .into_iter()
+            .filter_map(|movie| Some((ObjectId::parse_str(&movie.id).ok()?, movie)))
+            .collect())
+    }
+}
+
+struct UserLoader(Collection<User>);
+
+impl Loader<ObjectId> for UserLoader {
+    type Value = User;
+    type Error = Error;
+
+    async fn load(&self, ids: &[ObjectId]) -> Result<HashMap<ObjectId, User>, Error> {
+        let mut cursor = self.0.find(doc! {"_id": {"$in": ids}}, None).await.map_err(database_error)?;
+        let mut users = HashMap::new();
+        while cursor.advance().await.map_err(database_error)? {
+            match cursor.deserialize_current() {
+                Ok(user) => {
+                    if let Some(id) = user.id {
+                        users.insert(id, user);
+                    }
+                }
+                Err(error) => warn!(?error, "Skipping malformed user"),
+            }
+        }
+        Ok(users)
+    }
+}
+
+/// Who added each movie, keyed by movie id.
+struct CreatorLoader(Collection<User>);
+
+impl Loader<ObjectId> for CreatorLoader {
+    type Value = User;
+    type Error = Error;
+
+    async fn load(&self, movie_ids: &[ObjectId]) -> Result<HashMap<ObjectId, User>, Error> {
+        let mut cursor = self.0.find(doc! {"created_movies": {"$in": movie_ids}}, None).await.map_err(database_error)?;
+        let mut creators = HashMap::new();
+        while cursor.advance().await.map_err(database_error)? {
+            let user = match cursor.deserialize_current() {
+                Ok(user) => user,
+                Err(error) => {
+                    warn!(?error, "Skipping malformed user");
+                    continue;
+                }
+            };
+            for movie_id in user.created_movies.iter().filter(|id| movie_ids.contains(id)) {
+                creators.insert(*movie_id, user.clone());
+            }
+        }
+        Ok(creators)
+    }
+}
+
+#[derive(Deserialize)]
+struct ReviewGroup {
+    #[serde(rename = "_id")]
+    movie_id: ObjectId,
+    reviews: Vec<Activity>,
+}
+
+/// The best-rated reviews of each movie, newest first among equal ratings,
+/// keyed by movie id. Reviews by users who hide their ratings are left out,
+/// except for the caller's own.
+struct ReviewLoader {
+    activities: Collection<Activity>,
+    /// Name of the users collection, for looking up the authors' privacy.
+    users: String,
+    caller: Option<ObjectId>,
+}
+
+impl Loader<ObjectId> for ReviewLoader {
+    type Value = Vec<Activity>;
+    type Error = Error;
+
+    async fn load(&self, movie_ids: &[ObjectId]) -> Result<HashMap<ObjectId, Vec<Activity>>, Error> {
+        let pipeline = [
+            doc! {"$match": {"kind": "review_posted", "movie_id": {"$in": movie_ids}, "review": {"$type": "string"}}},
+            doc! {"$lookup": {"from": &self.users, "localField": "actor", "foreignField": "_id", "as": "author"}},
+            doc! {"$match": {"$or": [{"author.privacy.hide_ratings": {"$ne": true}}, {"actor": self.caller}]}},
+            doc! {"$unset": "author"},
+            doc! {"$sort": {"rating": -1, "created_at": -1}},
+            doc! {"$group": {"_id": "$movie_id", "reviews": {"$push": "$$ROOT"}}},
+            doc! {"$project": {"reviews": {"$slice": ["$reviews", MAX_REVIEWS as i64]}}},
+        ];
+        let mut cursor = self.activities.aggregate(pipeline, None).await.map_err(database_error)?.with_type::<ReviewGroup>();
+        let mut reviews = HashMap::new();
+        while cursor.advance().await.map_err(database_error)? {
+            match cursor.deserialize_current() {
+                Ok(group) => {
+                    reviews.insert(group.movie_id, group.reviews);
+                }
+                Err(error) => warn!(?error, "Skipping malformed reviews"),
+            }
+        }
+        Ok(reviews)
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Every movie, in the order they were added.
+    async fn movies(&self, ctx: &Context<'_>, first: Option<i32>, after: Option<String>) -> Result<Connection<String, MovieResponse>, Error> {
+        let movies = ctx.data::<Collection<Movie>>()?;
+        connection::query(after, None, first, None, |after: Option<String>, _, first, _| async move {
+            let first = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+            let filter = match &after {
+                Some(after) => doc! {"_id": {"$gt": parse_id(after)?}},
+                None => Document::new(),
+            };
+            // One more than asked for tells whether there is a next page.
+            let options = FindOptions::builder().sort(doc! {"_id": 1}).limit(first as i64 + 1).build();
+            let cursor = movies.find(filter, options).await.map_err(database_error)?;
+            let mut page = collect_movies(cursor).await.map_err(graphql_error)?;
+            let has_next_page = page.len() > first;
+            page.truncate(first);
+            let mut connection = Connection::new(after.is_some(), has_next_page);
+            connection.edges.extend(page.into_iter().map(|movie| Edge::new(movie.id.clone(), movie)));
+            Ok::<_, Error>(connection)
+        })
+        .await
+    }
+
+    async fn movie(&self, ctx: &Context<'_>, id: ID) -> Result<Option<MovieResponse>, Error> {
+        ctx.data::<DataLoader<MovieLoader>>()?.load_one(parse_id(&id)?).await
+    }
+
+    async fn user(&self, ctx: &Context<'_>, name: String) -> Result<Option<User>, Error> {
+        let users = ctx.data::<Collection<User>>()?;
+        user_by_name(&name, users).await.map_err(database_error)
+    }
+
+    /// The caller, or null when not logged in.
+    async fn me(&self, ctx: &Context<'_>) -> Result<Option<User>, Error> {
+        Ok(ctx.data::<Caller>()?.0.clone())
+    }
+}
+
+#[Object(name = "Movie")]
+impl MovieResponse {
+    async fn id(&self) -> ID {
+        ID(self.id.clone())
+    }
+
+    async fn title(&self) -> &str {
+        &self.title
+    }
+
+    async fn author(&self) -> &str {
+        &self.author
+    }
+
+    /// Served from `/api/v1/thumbnails/{imageUrl}`.
+    async fn image_url(&self) -> &str {
+        &self.image_url
+    }
+
+    async fn avg_rating(&self) -> f32 {
+        self.avg_rating
+    }
+
+    async fn num_ratings(&self) -> u32 {
+        self.num_ratings
+    }
+
+    /// Who added the movie, if they still have an account.
+    async fn creator(&self, ctx: &Context<'_>) -> Result<Option<User>, Error> {
+        ctx.data::<DataLoader<CreatorLoader>>()?.load_one(parse_id(&self.id)?).await
+    }
+
+    /// The caller's rating, or null when not logged in or not rated.
+    async fn my_rating(&self, ctx: &Context<'_>) -> Result<Option<f64>, Error> {
+        let Some(caller) = &ctx.data::<Caller>()?.0 else {
+            return Ok(None);
+        };
+        let id = parse_id(&self.id)?;
+        Ok(caller.movie_ratings.iter().find(|&&(movie_id, _)| movie_id == id).map(|&(_, rating)| rating))
+    }
+
+    /// The best-rated reviews first.
+    async fn reviews(&self, ctx: &Context<'_>, #[graphql(default_with = "DEFAULT_REVIEWS", validator(maximum = 20))] first: usize) -> Result<Vec<Activity>, Error> {
+        let reviews = ctx.data::<DataLoader<ReviewLoader>>()?.load_one(parse_id(&self.id)?).await?;
+        Ok(reviews.unwrap_or_default().into_iter().take(first).collect())
+    }
+}
+
+/// A rating on a user's profile.
+struct Rating {
+    movie_id: ObjectId,
+    rating: f64,
+}
+
+#[Object]
+impl Rating {
+    /// Null if the movie has since been deleted.
+    async fn movie(&self, ctx: &Context<'_>) -> Result<Option<MovieResponse>, Error> {
+        ctx.data::<DataLoader<MovieLoader>>()?.load_one(self.movie_id).await
+    }
+
+    async fn rating(&self) -> f64 {
+        self.rating
+    }
+}
+
+#[Object(name = "User")]
+impl User {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// When the account was created, as RFC 3339.
+    async fn joined(&self) -> Option<String> {
+        join_date(self).and_then(|date| date.try_to_rfc3339_string().ok())
+    }
+
+    async fn created_movies(&self, ctx: &Context<'_>) -> Result<Vec<MovieResponse>, Error> {
+        let mut movies = ctx.data::<DataLoader<MovieLoader>>()?.load_many(self.created_movies.iter().copied()).await?;
+        Ok(self.created_movies.iter().filter_map(|id| movies.remove(id)).collect())
+    }
+
+    async fn ratings_hidden(&self) -> bool {
+        self.privacy.hide_ratings
+    }
+
+    /// Most recent first. Null if the user hides their ratings, unless they
+    /// are asking for their own.
+    async fn ratings(&self, ctx: &Context<'_>, #[graphql(default_with = "DEFAULT_PAGE_SIZE", validator(maximum = 100))] first: usize) -> Result<Option<Vec<Rating>>, Error> {
+        let caller = &ctx.data::<Caller>()?.0;
+        let is_self = caller.as_ref().is_some_and(|caller| caller.id.is_some() && caller.id == self.id);
+        if self.privacy.hide_ratings && !is_self {
+            return Ok(None);
+        }
+        // `movie_ratings` is appended to as ratings come in, so the tail is the most recent.
+        let ratings = self.movie_ratings.iter().rev().take(first);
+        Ok(Some(ratings.map(|&(movie_id, rating)| Rating { movie_id, rating }).collect()))
+    }
+}
+
+#[Object(name = "Review")]
+impl Activity {
+    /// Null if the author has since deleted their account.
+    async fn author(&self, ctx: &Context<'_>) -> Result<Option<User>, Error> {
+        ctx.data::<DataLoader<UserLoader>>()?.load_one(self.actor).await
+    }
+
+    async fn rating(&self) -> Option<f64> {
+        self.rating
+    }
+
+    async fn text(&self) -> &str {
+        self.review.as_deref().unwrap_or_default()
+    }
+
+    /// As RFC 3339.
+    async fn posted_at(&self) -> String {
+        self.created_at.try_to_rfc3339_string().unwrap_or_default()
+    }
+}
+
+/// Runs a query as the caller. Loaders are made per request so batches
+/// never mix data between callers.
+async fn execute(request: async_graphql::Request, auth: Option<Authenticated>, schema: &CinemaSchema, server_data: &ServerData) -> GenericJsonResponse {
+    let caller = auth.map(|auth| auth.user);
+    let reviews = ReviewLoader {
+        activities: server_data.activities.clone(),
+        users: server_data.users.name().to_string(),
+        caller: caller.as_ref().and_then(|caller| caller.id),
+    };
+    let request = request
+        .data(Caller(caller))
+        .data(server_data.movies.clone())
+        .data(server_data.users.clone())
+        .data(DataLoader::new(MovieLoader(server_data.movies.clone()), tokio::spawn))
+        .data(DataLoader::new(UserLoader(server_data.users.clone()), tokio::spawn))
+        .data(DataLoader::new(CreatorLoader(server_data.users.clone()), tokio::spawn))
+        .data(DataLoader::new(reviews, tokio::spawn));
+    GenericJsonResponse::ok(&schema.execute(request).await)
+}
+
+#[derive(FromForm)]
+pub struct QueryParams<'r> {
+    query: &'r str,
+    #[field(name = "operationName")]
+    operation_name: Option<&'r str>,
+    /// A JSON object.
+    variables: Option<&'r str>,
+}
+
+/// A query in the URL. API tokens with only the `read` scope must use this
+/// rather than `POST`, as for the REST API.
+#[get("/graphql?<params..>")]
+pub async fn graphql_query(params: QueryParams<'_>, auth: Option<Authenticated>, schema: &State<CinemaSchema>, server_data: &State<ServerData>) -> GenericJsonResponse {
+    let mut request = async_graphql::Request::new(params.query);
+    if let Some(operation_name) = params.operation_name {
+        request = request.operation_name(operation_name);
+    }
+    if let Some(variables) = params.variables {
+        match serde_json::from_str(variables) {
+            Ok(variables) => request = request.variables(Variables::from_json(variables)),
+            Err(_) => return GenericJsonResponse::error(Status::BadRequest, "`variables` must be a JSON object"),
+        }
+    }
+    execute(request, auth, schema, server_data).await
+}
+
+#[post("/graphql", format = "json", data = "<request>")]
+pub async fn graphql_request(request: Json<async_graphql::Request>, auth: Option<Authenticated>, schema: &State<CinemaSchema>, server_data: &State<ServerData>) -> GenericJsonResponse {
+    execute(request.0, auth, schema, server_data).await
+}
+
+/// Only mounted in the `dev` profile.
+#[get("/graphiql")]
+pub fn graphiql() -> RawHtml<String> {
+    RawHtml(GraphiQLSource::build().endpoint("/graphql").title("Cinema Score GraphQL").finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{schema, Caller};
+    use async_graphql::{value, Request};
+    use rocket::async_test;
+
+    #[test]
+    fn test_schema() {
+        let sdl = schema().sdl();
+        assert!(sdl.contains("type MovieConnection"));
+        assert!(sdl.contains("myRating: Float"));
+        assert!(sdl.contains("reviews(first: Int! = 3): [Review!]!"));
+    }
+
+    #[async_test]
+    async fn test_anonymous_me() {
+        let request = Request::new("{ me { name } }").data(Caller(None));
+        let response = schema().execute(request).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert_eq!(response.data, value!({"me": null}));
+    }
+
+    #[async_test]
+    async fn test_depth_limit() {
+        let query = "{ me { createdMovies { creator { createdMovies { creator { createdMovies { creator { createdMovies { creator { createdMovies { creator { createdMovies { creator { name } } } } } } } } } } } } } }";
+        let response = schema().execute(Request::new(query).data(Caller(None))).await;
+        assert!(response.errors.iter().any(|error| error.message.contains("nested too deep")), "{:?}", response.errors);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::ServerData;
+use mongodb::bson::doc;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long a dependency may take to answer before it counts as down.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug, Clone)]
+struct Check {
+    status: &'static str,
+    latency_ms: u64,
+    /// Short reason when down; the details are logged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn is_up(&self) -> bool {
+        self.status == "up"
+    }
+}
+
+/// Runs `probe` with [`CHECK_TIMEOUT`], timing it.
+async fn check<F, E>(name: &str, probe: F) -> Check
+where
+    F: Future<Output = Result<(), E>>,
+    E: std::fmt::Debug,
+{
+    let started = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, probe).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(error)) => {
+            warn!(dependency = name, ?error, "Health check failed");
+            Some("unreachable".to_string())
+        }
+        Err(_) => {
+            warn!(dependency = name, timeout = ?CHECK_TIMEOUT, "Health check timed out");
+            Some("timed out".to_string())
+        }
+    };
+    Check {
+        status: if error.is_none() { "up" } else { "down" },
+        latency_ms,
+        error,
+    }
+}
+
+/// Whether the process is running. Never touches a dependency, so a slow
+/// database does not get the instance restarted.
+#[get("/health/live")]
+pub fn live() -> Json<Value> {
+    Json(json!({"status": "ok"}))
+}
+
+/// Whether the instance can serve traffic: pings the database and the blob
+/// container, and answers 503 if either is down.
+#[get("/health/ready")]
+pub async fn ready(server_data: &State<ServerData>) -> Custom<Json<Value>> {
+    let database = check("database", async {
+        server_data.database.run_command(doc! {"ping": 1}, None).await.map(|_| ())
+    });
+    let storage = check("storage", async {
+        let container = server_data.blob_client_builder.clone().container_client(&server_data.container_name);
+        container.get_properties().await.map(|_| ())
+    });
+    let (database, storage) = tokio::join!(database, storage);
+
+    let ready = database.is_up() && storage.is_up();
+    let body = json!({
+        "status": if ready { "ok" } else { "unavailable" },
+        "checks": {"database": database, "storage": storage},
+    });
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+    Custom(status, Json(body))
+}
@@ -0,0 +1,422 @@
+use crate::auth::hash_token;
+use crate::config::Secret;
+use crate::{database_error, GenericJsonResponse, ServerData, User};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::IndexOptions;
+use mongodb::{Collection, IndexModel};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{post, Build, Rocket, State};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+const ISSUER: &str = "cinema-score";
+const REFRESH_TOKEN_PREFIX: &str = "rt_";
+/// HS256 keys shorter than the hash output weaken the signature.
+const MIN_KEY_LEN: usize = 32;
+
+/// How callers prove who they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// `login` sets `username`/`id` cookies checked against the users collection.
+    Session,
+    /// `login` returns a short-lived access JWT, checked by signature alone,
+    /// and a refresh token for getting the next one. Session cookies are ignored.
+    Jwt,
+}
+
+/// The `auth` section of `Rocket.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    #[serde(default = "default_access_token_minutes")]
+    pub access_token_minutes: u64,
+    #[serde(default = "default_refresh_token_days")]
+    pub refresh_token_days: u64,
+}
+
+fn default_access_token_minutes() -> u64 {
+    15
+}
+
+fn default_refresh_token_days() -> u64 {
+    30
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            mode: AuthMode::Session,
+            access_token_minutes: default_access_token_minutes(),
+            refresh_token_days: default_refresh_token_days(),
+        }
+    }
+}
+
+/// Signing keys by `kid`. The first key in `JWT_KEYS` signs new tokens; the
+/// others only verify, so a key can be rotated out once the tokens it signed
+/// have expired.
+pub struct JwtKeys {
+    current: String,
+    keys: HashMap<String, (EncodingKey, DecodingKey)>,
+}
+
+impl JwtKeys {
+    /// Parses `kid:secret` pairs separated by commas.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut current = None;
+        let mut keys = HashMap::new();
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let Some((kid, secret)) = entry.split_once(':') else {
+                return Err(format!("JWT key `{}` must be written as kid:secret", entry));
+            };
+            if kid.is_empty() {
+                return Err("JWT key ids must not be empty".to_string());
+            }
+            if secret.len() < MIN_KEY_LEN {
+                return Err(format!("JWT key `{}` must be at least {} bytes long", kid, MIN_KEY_LEN));
+            }
+            let key = (EncodingKey::from_secret(secret.as_bytes()), DecodingKey::from_secret(secret.as_bytes()));
+            if keys.insert(kid.to_string(), key).is_some() {
+                return Err(format!("JWT key id `{}` is used twice", kid));
+            }
+            current.get_or_insert_with(|| kid.to_string());
+        }
+        match current {
+            Some(current) => Ok(JwtKeys { current, keys }),
+            None => Err("JWT_KEYS must contain at least one key".to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Claims {
+    /// Hex id of the user.
+    pub sub: String,
+    pub name: String,
+    iss: String,
+    iat: u64,
+    exp: u64,
+}
+
+/// Auth settings shared by the login handler and the auth guard.
+pub struct Jwt {
+    pub config: AuthConfig,
+    keys: Option<JwtKeys>,
+}
+
+impl Jwt {
+    pub fn new(config: AuthConfig, keys: Option<JwtKeys>) -> Self {
+        Jwt { config, keys }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.mode == AuthMode::Jwt
+    }
+
+    fn access_token(&self, user_id: ObjectId, name: &str, now: u64) -> Result<String, String> {
+        let keys = self.keys.as_ref().ok_or("No JWT keys configured")?;
+        let (encoding_key, _) = &keys.keys[&keys.current];
+        let claims = Claims {
+            sub: user_id.to_hex(),
+            name: name.to_string(),
+            iss: ISSUER.to_string(),
+            iat: now,
+            exp: now + self.config.access_token_minutes * 60,
+        };
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(keys.current.clone());
+        jsonwebtoken::encode(&header, &claims, encoding_key).map_err(|error| error.to_string())
+    }
+
+    /// Checks the signature, issuer and expiry of an access token.
+    pub fn verify(&self, token: &str) -> Result<Claims, &'static str> {
+        let keys = self.keys.as_ref().ok_or("JWT authentication is not configured")?;
+        let header = jsonwebtoken::decode_header(token).map_err(|_| "Malformed access token")?;
+        let Some((_, decoding_key)) = header.kid.as_ref().and_then(|kid| keys.keys.get(kid)) else {
+            return Err("Access token was signed with an unknown key");
+        };
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[ISSUER]);
+        match jsonwebtoken::decode::<Claims>(token, decoding_key, &validation) {
+            Ok(data) => Ok(data.claims),
+            Err(error) if *error.kind() == jsonwebtoken::errors::ErrorKind::ExpiredSignature => Err("Access token has expired"),
+            Err(_) => Err("Invalid access token"),
+        }
+    }
+}
+
+/// Reads the `auth` configuration and, in JWT mode, the keys from
+/// [`crate::config::AppConfig::jwt_keys`]. Refuses to launch in JWT mode
+/// without usable keys.
+pub struct JwtFairing(pub Option<Secret>);
+
+#[rocket::async_trait]
+impl Fairing for JwtFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Authentication mode",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = if rocket.figment().find_value("auth").is_ok() {
+            match rocket.figment().extract_inner::<AuthConfig>("auth") {
+                Ok(config) => config,
+                Err(error) => {
+                    error!(%error, "Invalid auth configuration");
+                    return Err(rocket);
+                }
+            }
+        } else {
+            AuthConfig::default()
+        };
+        let keys = match (config.mode, &self.0) {
+            (AuthMode::Session, _) => None,
+            (AuthMode::Jwt, Some(value)) => match JwtKeys::parse(value.expose()) {
+                Ok(keys) => Some(keys),
+                Err(error) => {
+                    error!(%error, "Invalid JWT keys");
+                    return Err(rocket);
+                }
+            },
+            (AuthMode::Jwt, None) => {
+                error!("auth.mode is \"jwt\" but no JWT keys are configured");
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(Jwt::new(config, keys)))
+    }
+}
+
+/// A refresh token. Each one can be used once; using it returns a new one in
+/// the same family. Presenting a used token again means it was copied, so
+/// the whole family is revoked.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RefreshToken {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    user_id: ObjectId,
+    family: String,
+    token_hash: String,
+    created_at: DateTime,
+    expires_at: DateTime,
+    #[serde(default)]
+    used_at: Option<DateTime>,
+    #[serde(default)]
+    revoked_at: Option<DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct TokenPair {
+    access_token: String,
+    token_type: String,
+    /// Seconds until the access token expires.
+    expires_in: u64,
+    refresh_token: String,
+    refresh_expires_in: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Looks refresh tokens up by hash, and lets Mongo drop them once expired.
+pub async fn create_indexes(refresh_tokens: Collection<RefreshToken>) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! {"token_hash": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"expires_at": 1})
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build(),
+    ];
+    if let Err(error) = refresh_tokens.create_indexes(indexes, None).await {
+        error!(?error, "Failed to create refresh token indexes");
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Issues an access token and a refresh token for `user`. Pass the family of
+/// the refresh token being rotated, or `None` for a fresh login.
+pub async fn issue_tokens(user: &User, family: Option<String>, jwt: &Jwt, server_data: &ServerData) -> Result<TokenPair, GenericJsonResponse> {
+    let Some(user_id) = user.id else {
+        return Err(GenericJsonResponse::error(Status::InternalServerError, "User has no id"));
+    };
+    let now = unix_now();
+    let access_token = jwt.access_token(user_id, &user.name, now).map_err(|error| {
+        error!(%error, "Failed to sign access token");
+        GenericJsonResponse::error(Status::InternalServerError, "Failed to issue access token")
+    })?;
+
+    let refresh_token = format!("{}{}{}", REFRESH_TOKEN_PREFIX, Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let refresh_expires_in = jwt.config.refresh_token_days * 24 * 60 * 60;
+    let created_at = DateTime::now();
+    let document = RefreshToken {
+        id: None,
+        user_id,
+        family: family.unwrap_or_else(|| Uuid::new_v4().simple().to_string()),
+        token_hash: hash_token(&refresh_token),
+        created_at,
+        expires_at: DateTime::from_millis(created_at.timestamp_millis() + refresh_expires_in as i64 * 1000),
+        used_at: None,
+        revoked_at: None,
+    };
+    server_data.refresh_tokens.insert_one(document, None).await.map_err(database_error)?;
+
+    Ok(TokenPair {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: jwt.config.access_token_minutes * 60,
+        refresh_token,
+        refresh_expires_in,
+    })
+}
+
+/// Revokes every refresh token of a user, e.g. after a password change.
+/// Access tokens already issued stay valid until they expire.
+pub async fn revoke_user_tokens(user_id: Option<ObjectId>, server_data: &ServerData) -> Result<(), GenericJsonResponse> {
+    let update = doc! {"$set": {"revoked_at": DateTime::now()}};
+    server_data
+        .refresh_tokens
+        .update_many(doc! {"user_id": user_id, "revoked_at": null}, update, None)
+        .await
+        .map_err(database_error)?;
+    Ok(())
+}
+
+/// Revokes the family `refresh_token` belongs to, e.g. on logout. Unknown
+/// tokens are ignored.
+pub async fn revoke_family(refresh_token: &str, server_data: &ServerData) -> Result<(), GenericJsonResponse> {
+    let filter = doc! {"token_hash": hash_token(refresh_token)};
+    let Some(token) = server_data.refresh_tokens.find_one(filter, None).await.map_err(database_error)? else {
+        return Ok(());
+    };
+    let revoke = doc! {"$set": {"revoked_at": DateTime::now()}};
+    server_data
+        .refresh_tokens
+        .update_many(doc! {"family": &token.family, "revoked_at": null}, revoke, None)
+        .await
+        .map_err(database_error)?;
+    Ok(())
+}
+
+fn invalid_refresh_token() -> GenericJsonResponse {
+    GenericJsonResponse::error(Status::Unauthorized, "Invalid or expired refresh token")
+}
+
+/// Trades a refresh token for a new token pair. Only in JWT mode; each
+/// refresh token works once.
+#[utoipa::path(
+    tag = "sessions",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "A new token pair", body = TokenPair),
+        (status = 401, description = "The refresh token is invalid, expired or used", body = crate::api::Problem),
+    )
+)]
+#[post("/sessions/refresh", format = "json", data = "<request>")]
+pub async fn refresh(request: Json<RefreshRequest>, jwt: &State<Jwt>, server_data: &State<ServerData>) -> GenericJsonResponse {
+    if !jwt.enabled() {
+        return GenericJsonResponse::error(Status::NotFound, "Token refresh is only available in JWT mode");
+    }
+    let filter = doc! {"token_hash": hash_token(&request.refresh_token), "revoked_at": null};
+    let token = match server_data.refresh_tokens.find_one(filter, None).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return invalid_refresh_token(),
+        Err(error) => return database_error(error),
+    };
+    if token.expires_at < DateTime::now() {
+        return invalid_refresh_token();
+    }
+
+    // Claiming the token with `used_at: null` in the filter means only one of
+    // two concurrent refreshes wins; the loser is treated as a replay.
+    let claim = doc! {"$set": {"used_at": DateTime::now()}};
+    let claimed = match server_data.refresh_tokens.update_one(doc! {"_id": token.id, "used_at": null}, claim, None).await {
+        Ok(result) => result.modified_count == 1,
+        Err(error) => return database_error(error),
+    };
+    if !claimed {
+        let revoke = doc! {"$set": {"revoked_at": DateTime::now()}};
+        if let Err(error) = server_data.refresh_tokens.update_many(doc! {"family": &token.family}, revoke, None).await {
+            return database_error(error);
+        }
+        warn!(user_id = %token.user_id, "Refresh token reused, revoked its family");
+        return invalid_refresh_token();
+    }
+
+    let user = match server_data.users.find_one(doc! {"_id": token.user_id}, None).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return invalid_refresh_token(),
+        Err(error) => return database_error(error),
+    };
+    match issue_tokens(&user, Some(token.family), jwt, server_data).await {
+        Ok(pair) => GenericJsonResponse::ok(&pair),
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthConfig, AuthMode, Jwt, JwtKeys};
+    use mongodb::bson::oid::ObjectId;
+
+    const OLD_KEY: &str = "old:0123456789abcdef0123456789abcdef";
+    const NEW_KEY: &str = "new:fedcba9876543210fedcba9876543210";
+
+    fn jwt(keys: &str) -> Jwt {
+        let config = AuthConfig { mode: AuthMode::Jwt, ..Default::default() };
+        Jwt::new(config, Some(JwtKeys::parse(keys).expect("valid keys")))
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert!(JwtKeys::parse("").is_err());
+        assert!(JwtKeys::parse("short:secret").is_err());
+        assert!(JwtKeys::parse("0123456789abcdef0123456789abcdef").is_err());
+        assert!(JwtKeys::parse(&format!("{},{}", OLD_KEY, OLD_KEY)).is_err());
+        let keys = JwtKeys::parse(&format!("{}, {}", NEW_KEY, OLD_KEY)).expect("valid keys");
+        assert_eq!(keys.current, "new");
+        assert_eq!(keys.keys.len(), 2);
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let now = super::unix_now();
+        let user_id = ObjectId::new();
+        let token = jwt(OLD_KEY).access_token(user_id, "alice", now).expect("signed token");
+
+        // Still accepted after a new key takes over signing...
+        let rotated = jwt(&format!("{},{}", NEW_KEY, OLD_KEY));
+        let claims = rotated.verify(&token).expect("valid token");
+        assert_eq!(claims.sub, user_id.to_hex());
+        assert_eq!(claims.name, "alice");
+        // ...but not once the old key is removed.
+        assert!(jwt(NEW_KEY).verify(&token).is_err());
+    }
+
+    #[test]
+    fn test_expired_token() {
+        let jwt = jwt(OLD_KEY);
+        let token = jwt.access_token(ObjectId::new(), "alice", super::unix_now() - 60 * 60).expect("signed token");
+        assert_eq!(jwt.verify(&token).unwrap_err(), "Access token has expired");
+        let tampered = format!("{}x", jwt.access_token(ObjectId::new(), "alice", super::unix_now()).expect("signed token"));
+        assert_eq!(jwt.verify(&tampered).unwrap_err(), "Invalid access token");
+    }
+}
@@ -0,0 +1,538 @@
+use crate::api;
+use crate::auth::Authenticated;
+use crate::csrf::CsrfVerified;
+use crate::validation;
+use crate::{database_error, movies_in_order, GenericJsonResponse, MovieResponse, ServerData, User};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{FindOptions, IndexOptions};
+use mongodb::{Collection, IndexModel};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, get, patch, post, put, State};
+use std::collections::{HashMap, HashSet};
+use tracing::{error, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+const PAGE_SIZE: i64 = 20;
+const MAX_TITLE_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 1000;
+const MAX_SLUG_BASE_LEN: usize = 48;
+/// Fresh suffixes to try when a new list's slug is already taken.
+const SLUG_RETRIES: usize = 3;
+
+/// A user-curated, ordered list of movies such as "Best of 2023".
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MovieList {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    owner: ObjectId,
+    title: String,
+    description: String,
+    slug: String,
+    public: bool,
+    movie_ids: Vec<ObjectId>,
+    created_at: DateTime,
+    updated_at: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ListCreateRequest {
+    title: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    public: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ListUpdateRequest {
+    title: Option<String>,
+    description: Option<String>,
+    public: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ListMovieRequest {
+    movie_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ListReorderRequest {
+    movie_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct ListSummaryResponse {
+    slug: String,
+    url: String,
+    owner: String,
+    title: String,
+    description: String,
+    public: bool,
+    num_movies: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct ListResponse {
+    slug: String,
+    url: String,
+    owner: String,
+    title: String,
+    description: String,
+    public: bool,
+    movies: Vec<MovieResponse>,
+}
+
+/// Turns a list title into a URL-safe slug with a random suffix, e.g.
+/// "Best of 2023!" becomes "best-of-2023-1a2b3c".
+pub async fn create_indexes(lists: Collection<MovieList>) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! {"slug": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder().keys(doc! {"owner": 1}).build(),
+    ];
+    if let Err(error) = lists.create_indexes(indexes, None).await {
+        error!(?error, "Failed to create list indexes");
+    }
+}
+
+fn make_slug(title: &str) -> String {
+    let mut base = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            base.push(c);
+        } else if !base.is_empty() && !base.ends_with('-') {
+            base.push('-');
+        }
+        if base.len() >= MAX_SLUG_BASE_LEN {
+            break;
+        }
+    }
+    let base = base.trim_end_matches('-');
+    let suffix = &Uuid::new_v4().simple().to_string()[..6];
+    if base.is_empty() {
+        format!("list-{}", suffix)
+    } else {
+        format!("{}-{}", base, suffix)
+    }
+}
+
+fn validate_details(title: Option<&str>, description: Option<&str>) -> Result<(), GenericJsonResponse> {
+    if let Some(title) = title {
+        if title.trim().is_empty() {
+            return Err(GenericJsonResponse::error(Status::UnprocessableEntity, "List title cannot be empty"));
+        }
+        if title.chars().count() > MAX_TITLE_LEN {
+            return Err(GenericJsonResponse::error(Status::UnprocessableEntity, "List title is too long"));
+        }
+    }
+    if let Some(description) = description {
+        if description.chars().count() > MAX_DESCRIPTION_LEN {
+            return Err(GenericJsonResponse::error(Status::UnprocessableEntity, "List description is too long"));
+        }
+    }
+    Ok(())
+}
+
+fn parse_movie_id(id: &str) -> Result<ObjectId, GenericJsonResponse> {
+    ObjectId::parse_str(id).map_err(|_| GenericJsonResponse::error(Status::BadRequest, "Invalid movie id"))
+}
+
+fn is_owner(list: &MovieList, user: Option<&User>) -> bool {
+    user.and_then(|user| user.id).is_some_and(|id| id == list.owner)
+}
+
+/// Finds a list by slug. Private lists are reported as missing to everyone
+/// but their owner so their existence is not leaked.
+async fn find_visible_list(slug: &str, user: Option<&User>, server_data: &ServerData) -> Result<MovieList, GenericJsonResponse> {
+    match server_data.lists.find_one(doc! {"slug": slug}, None).await {
+        Ok(Some(list)) if list.public || is_owner(&list, user) => Ok(list),
+        Ok(_) => Err(GenericJsonResponse::error(Status::NotFound, "List not found")),
+        Err(error) => Err(database_error(error)),
+    }
+}
+
+/// Finds a list by slug that the caller is allowed to modify.
+async fn find_owned_list(slug: &str, user: &User, server_data: &ServerData) -> Result<MovieList, GenericJsonResponse> {
+    let list = find_visible_list(slug, Some(user), server_data).await?;
+    if !is_owner(&list, Some(user)) {
+        return Err(GenericJsonResponse::error(Status::Forbidden, "Only the list owner can modify it"));
+    }
+    Ok(list)
+}
+
+async fn owner_names(owners: &[ObjectId], server_data: &ServerData) -> Result<HashMap<ObjectId, String>, GenericJsonResponse> {
+    let query = doc! {"_id": {"$in": owners}};
+    let mut names = HashMap::new();
+    let mut cursor = server_data.users.find(query, None).await.map_err(database_error)?;
+    while cursor.advance().await.map_err(database_error)? {
+        match cursor.deserialize_current() {
+            Ok(User { id: Some(id), name, .. }) => {
+                names.insert(id, name);
+            }
+            Ok(_) => (),
+            Err(error) => warn!(?error, "Skipping malformed user"),
+        }
+    }
+    Ok(names)
+}
+
+async fn list_summaries(filter: mongodb::bson::Document, options: FindOptions, server_data: &ServerData) -> Result<Vec<ListSummaryResponse>, GenericJsonResponse> {
+    let mut lists = Vec::new();
+    let mut cursor = server_data.lists.find(filter, options).await.map_err(database_error)?;
+    while cursor.advance().await.map_err(database_error)? {
+        match cursor.deserialize_current() {
+            Ok(list) => lists.push(list),
+            Err(error) => warn!(?error, "Skipping malformed list"),
+        }
+    }
+    let owners: Vec<ObjectId> = lists.iter().map(|list| list.owner).collect::<HashSet<_>>().into_iter().collect();
+    let names = owner_names(&owners, server_data).await?;
+    Ok(lists
+        .into_iter()
+        .map(|list| ListSummaryResponse {
+            url: format!("/lists/{}", list.slug),
+            owner: names.get(&list.owner).cloned().unwrap_or_default(),
+            num_movies: list.movie_ids.len(),
+            slug: list.slug,
+            title: list.title,
+            description: list.description,
+            public: list.public,
+        })
+        .collect())
+}
+
+async fn list_response(list: MovieList, server_data: &ServerData) -> Result<ListResponse, GenericJsonResponse> {
+    let names = owner_names(&[list.owner], server_data).await?;
+    let movies = movies_in_order(&list.movie_ids, server_data).await?;
+    Ok(ListResponse {
+        url: format!("/lists/{}", list.slug),
+        owner: names.get(&list.owner).cloned().unwrap_or_default(),
+        slug: list.slug,
+        title: list.title,
+        description: list.description,
+        public: list.public,
+        movies,
+    })
+}
+
+#[utoipa::path(
+    tag = "lists",
+    request_body = ListCreateRequest,
+    responses(
+        (status = 201, description = "The new list", body = ListResponse),
+        (status = 422, description = "The title or description is invalid", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[post("/lists", format = "json", data = "<list>")]
+pub async fn create_list(list: Json<ListCreateRequest>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let Some(owner) = user.id else {
+        return GenericJsonResponse::error(Status::InternalServerError, "User has no id");
+    };
+    if let Err(response) = validate_details(Some(&list.title), Some(&list.description)) {
+        return response;
+    }
+
+    let now = DateTime::now();
+    let mut list = MovieList {
+        id: None,
+        owner,
+        slug: make_slug(&list.0.title),
+        title: list.0.title.trim().to_string(),
+        description: list.0.description,
+        public: list.0.public,
+        movie_ids: Vec::new(),
+        created_at: now,
+        updated_at: now,
+    };
+    let mut retries = 0;
+    loop {
+        match server_data.lists.insert_one(list.clone(), None).await {
+            Ok(_) => break,
+            Err(error) if validation::is_duplicate_key(&error) && retries < SLUG_RETRIES => {
+                retries += 1;
+                list.slug = make_slug(&list.title);
+            }
+            Err(error) => return database_error(error),
+        }
+    }
+    match list_response(list, server_data).await {
+        Ok(response) => {
+            let mut response = GenericJsonResponse::ok(&response);
+            response.status = Status::Created;
+            response
+        }
+        Err(response) => response,
+    }
+}
+
+#[utoipa::path(
+    tag = "lists",
+    responses(
+        (status = 200, description = "Public lists, most recently updated first", body = [ListSummaryResponse]),
+        (status = 400, description = "A page out of range", body = crate::api::Problem),
+    )
+)]
+#[get("/lists?<page>")]
+pub async fn get_public_lists(page: Option<u64>, server_data: &State<ServerData>) -> GenericJsonResponse {
+    let skip = match api::page_offset(page.unwrap_or(0), PAGE_SIZE) {
+        Ok(skip) => skip,
+        Err(error) => return error.into(),
+    };
+    let options = FindOptions::builder()
+        .sort(doc! {"updated_at": -1})
+        .skip(skip)
+        .limit(PAGE_SIZE)
+        .build();
+    match list_summaries(doc! {"public": true}, options, server_data).await {
+        Ok(lists) => GenericJsonResponse::ok(&lists),
+        Err(response) => response,
+    }
+}
+
+#[utoipa::path(
+    tag = "lists",
+    responses(
+        (status = 200, description = "The caller's lists, public or not", body = [ListSummaryResponse]),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[get("/lists/mine")]
+pub async fn get_my_lists(auth: Authenticated, server_data: &State<ServerData>) -> GenericJsonResponse {
+    let user = auth.user;
+    let options = FindOptions::builder().sort(doc! {"updated_at": -1}).build();
+    match list_summaries(doc! {"owner": user.id}, options, server_data).await {
+        Ok(lists) => GenericJsonResponse::ok(&lists),
+        Err(response) => response,
+    }
+}
+
+#[utoipa::path(
+    tag = "lists",
+    responses(
+        (status = 200, description = "The list", body = ListResponse),
+        (status = 404, description = "No such list, or it is private", body = crate::api::Problem),
+    )
+)]
+#[get("/lists/<slug>")]
+pub async fn get_list(slug: &str, auth: Option<Authenticated>, server_data: &State<ServerData>) -> GenericJsonResponse {
+    visible_list_response(slug, auth.map(|auth| auth.user).as_ref(), server_data).await
+}
+
+async fn visible_list_response(slug: &str, user: Option<&User>, server_data: &ServerData) -> GenericJsonResponse {
+    let list = match find_visible_list(slug, user, server_data).await {
+        Ok(list) => list,
+        Err(response) => return response,
+    };
+    match list_response(list, server_data).await {
+        Ok(response) => GenericJsonResponse::ok(&response),
+        Err(response) => response,
+    }
+}
+
+#[utoipa::path(
+    tag = "lists",
+    request_body = ListUpdateRequest,
+    responses(
+        (status = 200, description = "The updated list", body = ListResponse),
+        (status = 404, description = "No such list owned by the caller", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[patch("/lists/<slug>", format = "json", data = "<update>")]
+pub async fn update_list(slug: &str, update: Json<ListUpdateRequest>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let list = match find_owned_list(slug, &user, server_data).await {
+        Ok(list) => list,
+        Err(response) => return response,
+    };
+    if let Err(response) = validate_details(update.title.as_deref(), update.description.as_deref()) {
+        return response;
+    }
+
+    let mut set = doc! {"updated_at": DateTime::now()};
+    if let Some(title) = &update.title {
+        set.insert("title", title.trim());
+    }
+    if let Some(description) = &update.description {
+        set.insert("description", description);
+    }
+    if let Some(public) = update.public {
+        set.insert("public", public);
+    }
+    if let Err(error) = server_data.lists.update_one(doc! {"_id": list.id}, doc! {"$set": set}, None).await {
+        return database_error(error);
+    }
+    visible_list_response(slug, Some(&user), server_data).await
+}
+
+#[utoipa::path(
+    tag = "lists",
+    responses(
+        (status = 200, description = "Deleted"),
+        (status = 404, description = "No such list owned by the caller", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[delete("/lists/<slug>")]
+pub async fn delete_list(slug: &str, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let list = match find_owned_list(slug, &user, server_data).await {
+        Ok(list) => list,
+        Err(response) => return response,
+    };
+    match server_data.lists.delete_one(doc! {"_id": list.id}, None).await {
+        Ok(_) => GenericJsonResponse::ok(&serde_json::json!({"message": "List deleted"})),
+        Err(error) => database_error(error),
+    }
+}
+
+#[utoipa::path(
+    tag = "lists",
+    request_body = ListMovieRequest,
+    responses(
+        (status = 200, description = "The updated list", body = ListResponse),
+        (status = 404, description = "No such list or movie", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[post("/lists/<slug>/movies", format = "json", data = "<movie>")]
+pub async fn add_list_movie(slug: &str, movie: Json<ListMovieRequest>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let list = match find_owned_list(slug, &user, server_data).await {
+        Ok(list) => list,
+        Err(response) => return response,
+    };
+    let movie_id = match parse_movie_id(&movie.movie_id) {
+        Ok(movie_id) => movie_id,
+        Err(response) => return response,
+    };
+    match server_data.movies.find_one(doc! {"_id": movie_id}, None).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return GenericJsonResponse::error(Status::NotFound, "Movie not found"),
+        Err(error) => return database_error(error),
+    }
+    if list.movie_ids.contains(&movie_id) {
+        return GenericJsonResponse::error(Status::Conflict, "Movie is already on the list");
+    }
+
+    // The `$ne` guard keeps two concurrent adds from inserting the same movie twice.
+    let filter = doc! {"_id": list.id, "movie_ids": {"$ne": movie_id}};
+    let update = doc! {"$push": {"movie_ids": movie_id}, "$set": {"updated_at": DateTime::now()}};
+    match server_data.lists.update_one(filter, update, None).await {
+        Ok(result) if result.matched_count == 0 => GenericJsonResponse::error(Status::Conflict, "Movie is already on the list"),
+        Ok(_) => visible_list_response(slug, Some(&user), server_data).await,
+        Err(error) => database_error(error),
+    }
+}
+
+#[utoipa::path(
+    tag = "lists",
+    responses(
+        (status = 200, description = "The updated list", body = ListResponse),
+        (status = 404, description = "No such list owned by the caller", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[delete("/lists/<slug>/movies/<movie_id>")]
+pub async fn remove_list_movie(slug: &str, movie_id: &str, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let list = match find_owned_list(slug, &user, server_data).await {
+        Ok(list) => list,
+        Err(response) => return response,
+    };
+    let movie_id = match parse_movie_id(movie_id) {
+        Ok(movie_id) => movie_id,
+        Err(response) => return response,
+    };
+    if !list.movie_ids.contains(&movie_id) {
+        return GenericJsonResponse::error(Status::NotFound, "Movie is not on the list");
+    }
+
+    let update = doc! {"$pull": {"movie_ids": movie_id}, "$set": {"updated_at": DateTime::now()}};
+    match server_data.lists.update_one(doc! {"_id": list.id}, update, None).await {
+        Ok(_) => visible_list_response(slug, Some(&user), server_data).await,
+        Err(error) => database_error(error),
+    }
+}
+
+/// Checks that `requested` contains exactly the movies already on the list,
+/// each once, and returns them in the requested order.
+fn reordered(current: &[ObjectId], requested: &[String]) -> Result<Vec<ObjectId>, GenericJsonResponse> {
+    let requested = requested.iter().map(|id| parse_movie_id(id)).collect::<Result<Vec<_>, _>>()?;
+    let unique: HashSet<&ObjectId> = requested.iter().collect();
+    let current_set: HashSet<&ObjectId> = current.iter().collect();
+    if unique.len() != requested.len() || unique != current_set {
+        return Err(GenericJsonResponse::error(
+            Status::UnprocessableEntity,
+            "New order must contain every movie on the list exactly once",
+        ));
+    }
+    Ok(requested)
+}
+
+#[utoipa::path(
+    tag = "lists",
+    request_body = ListReorderRequest,
+    responses(
+        (status = 200, description = "The updated list", body = ListResponse),
+        (status = 422, description = "The ids are not the list's movies", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[put("/lists/<slug>/movies", format = "json", data = "<order>")]
+pub async fn reorder_list(slug: &str, order: Json<ListReorderRequest>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let list = match find_owned_list(slug, &user, server_data).await {
+        Ok(list) => list,
+        Err(response) => return response,
+    };
+    let movie_ids = match reordered(&list.movie_ids, &order.movie_ids) {
+        Ok(movie_ids) => movie_ids,
+        Err(response) => return response,
+    };
+
+    // Matching on the old order makes a concurrent add/remove fail the
+    // reorder instead of being silently overwritten.
+    let filter = doc! {"_id": list.id, "movie_ids": &list.movie_ids};
+    let update = doc! {"$set": {"movie_ids": movie_ids, "updated_at": DateTime::now()}};
+    match server_data.lists.update_one(filter, update, None).await {
+        Ok(result) if result.matched_count == 0 => GenericJsonResponse::error(Status::Conflict, "List was modified, reload and try again"),
+        Ok(_) => visible_list_response(slug, Some(&user), server_data).await,
+        Err(error) => database_error(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{make_slug, reordered};
+    use mongodb::bson::oid::ObjectId;
+
+    #[test]
+    fn test_make_slug() {
+        let slug = make_slug("  Best of 2023! Team movie night ");
+        assert!(slug.starts_with("best-of-2023-team-movie-night-"));
+        assert_eq!(slug.len(), "best-of-2023-team-movie-night-".len() + 6);
+        assert!(make_slug("!!!").starts_with("list-"));
+        assert_ne!(make_slug("Same"), make_slug("Same"));
+    }
+
+    #[test]
+    fn test_reordered() {
+        let a = ObjectId::new();
+        let b = ObjectId::new();
+        let current = vec![a, b];
+        let order = reordered(&current, &[b.to_hex(), a.to_hex()]).unwrap();
+        assert_eq!(order, vec![b, a]);
+        assert!(reordered(&current, &[a.to_hex()]).is_err());
+        assert!(reordered(&current, &[a.to_hex(), a.to_hex()]).is_err());
+        assert!(reordered(&current, &[a.to_hex(), "nope".to_string()]).is_err());
+    }
+}
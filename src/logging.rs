@@ -0,0 +1,167 @@
+use crate::config::{LogFormat, LoggingConfig};
+use crate::otel;
+use opentelemetry_sdk::trace::Tracer;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::Request;
+use rocket::route::{self, Handler, Route};
+use rocket::{Data, Response};
+use std::time::Instant;
+use tracing::{field, info, info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The filter as `RUST_LOG` spells it: the default level, then one
+/// `target=level` directive per module.
+fn filter_directives(config: &LoggingConfig) -> String {
+    let mut directives = vec![config.level.clone()];
+    directives.extend(config.modules.iter().map(|(module, level)| format!("{}={}", module, level)));
+    directives.join(",")
+}
+
+/// Installs the global subscriber, which also receives Rocket's own `log`
+/// records, and exports spans through `tracer` if given. `RUST_LOG`, if set,
+/// replaces the configured levels. Only the first call has an effect, so
+/// tests can build the server repeatedly.
+pub fn init(config: &LoggingConfig, tracer: Option<Tracer>) -> Result<(), String> {
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) => EnvFilter::try_new(&directives),
+        Err(_) => EnvFilter::try_new(filter_directives(config)),
+    }
+    .map_err(|error| format!("Invalid log level: {}", error))?;
+    let (json, pretty) = match config.format {
+        LogFormat::Json => (Some(tracing_subscriber::fmt::layer().json().with_current_span(false).with_span_list(true)), None),
+        LogFormat::Pretty => (None, Some(tracing_subscriber::fmt::layer())),
+    };
+    let _ = tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(pretty)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .try_init();
+    Ok(())
+}
+
+/// The ID that ties together the log lines of one request.
+pub struct RequestId(pub String);
+
+/// Client-supplied IDs are kept only if they are short and printable, so
+/// they cannot forge log lines or bloat the header.
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+pub fn request_id<'r>(request: &'r Request<'_>) -> &'r str {
+    &request.local_cache(|| RequestId(Uuid::new_v4().to_string())).0
+}
+
+/// Takes the request ID from the `X-Request-Id` header, or assigns one, and
+/// echoes it in the response.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ID",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = request.headers().get_one(REQUEST_ID_HEADER).filter(|id| valid_request_id(id)).map(str::to_string);
+        request.local_cache(|| RequestId(id.unwrap_or_else(|| Uuid::new_v4().to_string())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_raw_header(REQUEST_ID_HEADER, request_id(request).to_string());
+    }
+}
+
+/// Runs a route's handler, guards included, inside a span carrying the
+/// request ID, route and, once authenticated, the user. The span continues
+/// the caller's trace if the request has a `traceparent` header. Logs the
+/// outcome and latency when it finishes.
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let route = request.route().map(|route| route.uri.to_string()).unwrap_or_default();
+        let span = info_span!(
+            "request",
+            otel.name = %format!("{} {}", request.method(), route),
+            otel.kind = "server",
+            otel.status_code = field::Empty,
+            http.status_code = field::Empty,
+            request_id = request_id(request),
+            method = %request.method(),
+            route = %route,
+            user = field::Empty,
+        );
+        span.set_parent(otel::parent_context(request));
+        let started = Instant::now();
+        let outcome = self.0.handle(request, data).instrument(span.clone()).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        let status = match &outcome {
+            route::Outcome::Success(response) => Some(response.status()),
+            route::Outcome::Error(status) => Some(*status),
+            route::Outcome::Forward(_) => None,
+        };
+        if let Some(status) = status {
+            span.record("http.status_code", status.code);
+            if status.code >= 500 {
+                span.record("otel.status_code", "ERROR");
+            }
+        }
+        span.in_scope(|| match &outcome {
+            route::Outcome::Success(response) => info!(status = response.status().code, latency_ms, "Request handled"),
+            route::Outcome::Error(status) => info!(status = status.code, latency_ms, "Request failed"),
+            route::Outcome::Forward(_) => info!(latency_ms, "Request forwarded"),
+        });
+        outcome
+    }
+}
+
+/// Wraps each route's handler in [`Traced`].
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{filter_directives, valid_request_id};
+    use crate::config::{LogFormat, LoggingConfig};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_request_id() {
+        assert!(valid_request_id("b7c2e0a4-6f1d-4c1e-9d3a-0f8e2b1c5a77"));
+        assert!(!valid_request_id(""));
+        assert!(!valid_request_id("two words"));
+        assert!(!valid_request_id("line\nbreak"));
+        assert!(!valid_request_id(&"a".repeat(129)));
+    }
+
+    #[test]
+    fn test_filter_directives() {
+        let config = LoggingConfig {
+            level: "info".to_string(),
+            format: LogFormat::Json,
+            modules: BTreeMap::from([("mongodb".to_string(), "warn".to_string()), ("cinema_score::auth".to_string(), "debug".to_string())]),
+        };
+        assert_eq!(filter_directives(&config), "info,cinema_score::auth=debug,mongodb=warn");
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::MailConfig;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info;
+use uuid::Uuid;
+
+/// A plain-text email.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[rocket::async_trait]
+pub trait MailSender: Send + Sync {
+    async fn send(&self, message: &Message) -> Result<(), String>;
+}
+
+/// Prints messages instead of sending them. The default, so development
+/// setups need no mail server.
+pub struct LogSender;
+
+#[rocket::async_trait]
+impl MailSender for LogSender {
+    async fn send(&self, message: &Message) -> Result<(), String> {
+        info!(to = %message.to, subject = %message.subject, body = %message.body, "Mail");
+        Ok(())
+    }
+}
+
+/// Writes each message to its own `.eml` file in a directory.
+pub struct FileSender {
+    dir: PathBuf,
+}
+
+impl FileSender {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileSender { dir: dir.into() }
+    }
+}
+
+#[rocket::async_trait]
+impl MailSender for FileSender {
+    async fn send(&self, message: &Message) -> Result<(), String> {
+        rocket::tokio::fs::create_dir_all(&self.dir).await.map_err(|error| error.to_string())?;
+        let path = self.dir.join(format!("{}.eml", Uuid::new_v4().simple()));
+        let contents = format!("To: {}\nSubject: {}\n\n{}\n", message.to, message.subject, message.body);
+        rocket::tokio::fs::write(path, contents).await.map_err(|error| error.to_string())
+    }
+}
+
+pub struct SmtpSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpSender {
+    /// Connects with STARTTLS, on port 587 unless `port` is given.
+    pub fn new(host: &str, port: Option<u16>, credentials: Option<(String, String)>, from: &str) -> Result<Self, String> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host).map_err(|error| error.to_string())?;
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        let from = from.parse().map_err(|error| format!("Invalid sender address {}: {}", from, error))?;
+        Ok(SmtpSender {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl MailSender for SmtpSender {
+    async fn send(&self, message: &Message) -> Result<(), String> {
+        let to: Mailbox = message.to.parse().map_err(|error| format!("Invalid recipient: {}", error))?;
+        let email = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&message.subject)
+            .body(message.body.clone())
+            .map_err(|error| error.to_string())?;
+        self.transport.send(email).await.map(|_| ()).map_err(|error| error.to_string())
+    }
+}
+
+/// Builds the sender chosen by the `mail` configuration: `smtp`, `file` or
+/// `log`.
+pub fn sender_from_config(config: &MailConfig) -> Result<Arc<dyn MailSender>, String> {
+    Ok(match config {
+        MailConfig::Smtp { host, port, credentials, from } => {
+            let credentials = credentials.as_ref().map(|(username, password)| (username.clone(), password.expose().to_string()));
+            Arc::new(SmtpSender::new(host, *port, credentials, from)?)
+        }
+        MailConfig::File { dir } => Arc::new(FileSender::new(dir.clone())),
+        MailConfig::Log => Arc::new(LogSender),
+    })
+}
@@ -2,57 +2,182 @@ use azure_storage::prelude::*;
 use azure_storage_blobs::prelude::*;
 use cookie::time::{Duration, OffsetDateTime};
 use dotenv::dotenv;
-use mongodb::bson::{doc, Document};
+use error::ApiError;
+use mongodb::bson::{doc, DateTime, Document};
 use mongodb::bson::oid::ObjectId;
+use mongodb::options::{ClientOptions, FindOneAndUpdateOptions, IndexOptions, ReturnDocument};
 use mongodb::Client;
 use mongodb::Collection;
+use mongodb::IndexModel;
 use rocket::fs::{FileServer, NamedFile};
-use rocket::http::{ContentType, Cookie, CookieJar, Status};
+use rocket::http::{ContentType, Cookie, CookieJar, SameSite, Status};
 use rocket::request::Request;
 use rocket::response;
 use rocket::response::Redirect;
-use rocket::routes;
+use rocket::{catchers, routes};
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
-use rocket::{get, post, delete, State, Rocket, Build, Response};
+use rocket::{get, post, put, delete, patch, State, Rocket, Build, Response};
 use serde_json::{json, to_string};
+use std::collections::HashMap;
 use std::io::Cursor;
+use std::net::IpAddr;
 use std::path::Path;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
+mod account;
+mod api;
+mod audit;
+mod auth;
+mod config;
+mod csrf;
+mod error;
+mod events;
+mod feed;
+mod graphql;
+mod health;
+mod jwt;
+mod lists;
+mod logging;
+mod mail;
+mod metrics;
+mod oidc;
+mod otel;
+mod password_reset;
+mod profiles;
+mod rate_limit;
+mod telemetry;
+mod throttle;
+mod tokens;
+mod two_factor;
+mod validation;
+
 struct ServerData {
+    database: mongodb::Database,
     users: Collection<User>,
     movies: Collection<Movie>,
+    lists: Collection<lists::MovieList>,
+    activities: Collection<feed::Activity>,
+    api_tokens: Collection<tokens::ApiToken>,
+    refresh_tokens: Collection<jwt::RefreshToken>,
+    oidc_logins: Collection<oidc::PendingLogin>,
+    password_resets: Collection<password_reset::PasswordReset>,
+    two_factor_challenges: Collection<two_factor::Challenge>,
+    audit_log: Collection<audit::AuditEntry>,
+    login_limiter: rate_limit::LoginLimiter,
+    mailer: Arc<dyn mail::MailSender>,
+    telemetry: telemetry::Telemetry,
+    events: events::EventBus,
+    /// Where the site is reachable, for links in emails.
+    public_url: String,
     blob_client_builder: ClientBuilder,
     container_name: String,
 }
 
 impl ServerData {
-    async fn new() -> Self {
-        dotenv().ok();
-        let connection_string = std::env::var("COSMOS_CONNECTION_STRING")
-            .expect("Set env variable COSMOS_PRIMARY_KEY first!");
-        let client = Client::with_uri_str(connection_string).await.unwrap();
-        let database_name = std::env::var("COSMOS_DB_NAME").unwrap();
-        let database = client.database(&database_name);
-        let users_coll_name = std::env::var("COSMOS_COLL_USERS_NAME").unwrap();
-        let movies_coll_name = std::env::var("COSMOS_COLL_MOVIES_NAME").unwrap();
-        let users_coll = database.collection::<User>(&users_coll_name);
-        let movies_coll = database.collection::<Movie>(&movies_coll_name);
-        let account = std::env::var("STORAGE_ACCOUNT").expect("missing STORAGE_ACCOUNT");
-        let access_key = std::env::var("STORAGE_ACCESS_KEY").expect("missing STORAGE_ACCOUNT_KEY");
-        let container_name = std::env::var("STORAGE_CONTAINER").expect("missing STORAGE_CONTAINER");
+    async fn new(config: &config::AppConfig, telemetry: telemetry::Telemetry) -> Self {
+        let database_config = &config.database;
+        let names = &database_config.collections;
+        // The error would echo the connection string, so only its kind is shown.
+        let mut client_options = match ClientOptions::parse(database_config.connection_string.expose()).await {
+            Ok(client_options) => client_options,
+            Err(error) => panic!("Invalid database.connection_string: {:?}", error.kind.as_ref()),
+        };
+        client_options.command_event_handler = Some(Arc::new(telemetry::MongoCommandEvents::new(telemetry.clone())));
+        let client = match Client::with_options(client_options) {
+            Ok(client) => client,
+            Err(error) => panic!("Invalid database.connection_string: {:?}", error.kind.as_ref()),
+        };
+        let database = client.database(&database_config.name);
+        let users_coll = database.collection::<User>(&names.users);
+        let movies_coll = database.collection::<Movie>(&names.movies);
+        let lists_coll = database.collection::<lists::MovieList>(&names.lists);
+        tokio::spawn(lists::create_indexes(lists_coll.clone()));
+        let activities_coll = database.collection::<feed::Activity>(&names.activities);
+        tokio::spawn(feed::create_indexes(activities_coll.clone()));
+        let api_tokens_coll = database.collection::<tokens::ApiToken>(&names.api_tokens);
+        tokio::spawn(tokens::create_indexes(api_tokens_coll.clone()));
+        let refresh_tokens_coll = database.collection::<jwt::RefreshToken>(&names.refresh_tokens);
+        tokio::spawn(jwt::create_indexes(refresh_tokens_coll.clone()));
+        let oidc_logins_coll = database.collection::<oidc::PendingLogin>(&names.oidc_logins);
+        tokio::spawn(oidc::create_indexes(oidc_logins_coll.clone()));
+        let password_resets_coll = database.collection::<password_reset::PasswordReset>(&names.password_resets);
+        tokio::spawn(password_reset::create_indexes(password_resets_coll.clone()));
+        let two_factor_challenges_coll = database.collection::<two_factor::Challenge>(&names.two_factor_challenges);
+        tokio::spawn(two_factor::create_indexes(two_factor_challenges_coll.clone()));
+        let audit_log_coll = database.collection::<audit::AuditEntry>(&names.audit_log);
+        tokio::spawn(audit::create_indexes(audit_log_coll.clone()));
+        tokio::spawn(create_user_indexes(users_coll.clone()));
+        // Instances behind a load balancer should share attempts through Mongo.
+        let login_attempt_store: Box<dyn rate_limit::AttemptStore> = match config.login_attempt_store {
+            config::AttemptStoreKind::Mongo => {
+                let login_attempts_coll = database.collection(&names.login_attempts);
+                tokio::spawn(rate_limit::create_indexes(login_attempts_coll.clone()));
+                Box::new(rate_limit::MongoAttemptStore::new(login_attempts_coll))
+            }
+            config::AttemptStoreKind::Memory => Box::new(rate_limit::MemoryAttemptStore::default()),
+        };
+        let storage = &config.storage;
+        let account = storage.account.clone();
+        let access_key = storage.access_key.expose().to_string();
+        let container_name = storage.container.clone();
         let storage_credentials = StorageCredentials::access_key(account.clone(), access_key);
-        let blob_client_builder = ClientBuilder::new(account, storage_credentials);
+        let blob_policy: Arc<dyn azure_core::Policy> = Arc::new(telemetry::BlobDependencyPolicy(telemetry.clone()));
+        let blob_client_builder = ClientBuilder::new(account, storage_credentials)
+            .client_options(azure_core::ClientOptions::default().per_call_policies(vec![blob_policy]));
         ServerData {
+            database,
             users: users_coll,
             movies: movies_coll,
+            lists: lists_coll,
+            activities: activities_coll,
+            api_tokens: api_tokens_coll,
+            refresh_tokens: refresh_tokens_coll,
+            oidc_logins: oidc_logins_coll,
+            password_resets: password_resets_coll,
+            two_factor_challenges: two_factor_challenges_coll,
+            audit_log: audit_log_coll,
+            login_limiter: rate_limit::LoginLimiter::new(login_attempt_store),
+            mailer: mail::sender_from_config(&config.mail).unwrap_or_else(|error| panic!("Invalid mail configuration: {}", error)),
+            public_url: config.public_url.clone(),
+            telemetry,
+            events: events::EventBus::new(),
             blob_client_builder,
             container_name,
         }
     }
 }
 
+/// Unique indexes that stop two registrations from claiming the same name or
+/// email, or two users from linking the same external identity. `name_key` is
+/// sparse because users created before it existed lack the field.
+async fn create_user_indexes(users: Collection<User>) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! {"name": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"name_key": 1})
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"identities.provider": 1, "identities.subject": 1})
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"email": 1})
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build(),
+    ];
+    if let Err(error) = users.create_indexes(indexes, None).await {
+        error!(?error, "Failed to create user indexes");
+    }
+}
+
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 struct Movie {
     title: String,
@@ -62,7 +187,7 @@ struct Movie {
     num_ratings: u32,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+#[derive(Default, Serialize, Deserialize, Debug, Clone, ToSchema)]
 struct MovieResponse {
     id: String,
     title: String,
@@ -72,62 +197,118 @@ struct MovieResponse {
     num_ratings: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 struct MovieUploadRequest {
     title: String,
     author: String,
     image: Vec<u8>,
 }
 
+impl Movie {
+    /// The fields an edit can change, for the audit log.
+    fn audit_doc(&self) -> Document {
+        doc! {"title": &self.title, "author": &self.author, "image_url": &self.image_url}
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct MovieUpdateRequest {
+    title: Option<String>,
+    author: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct RatingRequest {
+    /// From 1 to 5.
+    rating: f64,
+    /// Shown in followers' feeds and among the movie's top reviews.
+    #[serde(default)]
+    review: Option<String>,
+}
+
+const MAX_REVIEW_LEN: usize = 2000;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct User {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
     name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_key: Option<String>,
+    /// Optional; needed to reset a forgotten password. Stored normalized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
     password: String,
     uuid: String,
     movie_ratings: Vec<(ObjectId, f64)>,
-    created_movies: Vec<ObjectId>
+    created_movies: Vec<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime>,
+    #[serde(default)]
+    privacy: profiles::PrivacySettings,
+    #[serde(default)]
+    following: Vec<ObjectId>,
+    #[serde(default)]
+    failed_logins: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    locked_until: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    identities: Vec<oidc::ExternalIdentity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    two_factor: Option<two_factor::TwoFactor>,
+    /// May read the audit log. Only ever set directly in the database.
+    #[serde(default)]
+    admin: bool,
+    /// Set on accounts created through an identity provider until their user
+    /// picks a password; `password` is random until then.
+    #[serde(default)]
+    password_unset: bool,
 }
 
 impl User {
-    fn as_doc(&self) -> Document {
-        doc! {
-            "name": &self.name,
-            "password": &self.password,
-            "uuid": &self.uuid,
-            "movie_ratings": self.movie_ratings.iter().map(|&(ref id, rating)| doc! {"movie_id": id, "rating": rating}).collect::<Vec<Document>>(),
-            "created_movies": &self.created_movies
-        }
+    /// Whether `password` is this user's. Never true for a user without a
+    /// password of their own.
+    fn password_matches(&self, password: &str) -> bool {
+        !self.password_unset && self.password == password
     }
-}
 
-impl Into<Option<Document>> for User {
-    fn into(self) -> Option<Document> {
-        Some(doc! {
-            "name": self.name,
-            "password": self.password,
-        })
+    /// A user that has not been stored yet, with a fresh session id.
+    /// `name` must already be normalized.
+    fn new(name: String, password: String) -> Self {
+        User {
+            id: None,
+            name_key: Some(validation::username_key(&name)),
+            name,
+            email: None,
+            password,
+            uuid: Uuid::new_v4().to_string(),
+            movie_ratings: Vec::new(),
+            created_movies: Vec::new(),
+            created_at: Some(DateTime::now()),
+            privacy: Default::default(),
+            following: Vec::new(),
+            failed_logins: 0,
+            locked_until: None,
+            identities: Vec::new(),
+            two_factor: None,
+            admin: false,
+            password_unset: false,
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 struct UserLogin {
     name: String,
     password: String,
 }
 
-impl Into<Option<Document>> for UserLogin {
-    fn into(self) -> Option<Document> {
-        Some(doc! {
-            "name": self.name,
-            "password": self.password,
-        })
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct UserLogout {
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct UserRegistration {
     name: String,
-    uuid: String,
+    password: String,
+    #[serde(default)]
+    email: Option<String>,
 }
 
 
@@ -138,10 +319,15 @@ struct GenericJsonResponse {
     cookies: Vec<Cookie<'static>>,
 }
 
+/// Error statuses are sent as `application/problem+json`; see [`error::ApiError`].
 impl<'r> response::Responder<'r, 'static> for GenericJsonResponse {
-    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if self.status.code >= 500 {
+            telemetry::note_failure(request, &self.json);
+        }
+        let content_type = if self.status.code >= 400 { error::problem_content_type() } else { ContentType::JSON };
         let mut binding = Response::build();
-        binding.status(self.status).header(ContentType::JSON);
+        binding.status(self.status).header(content_type);
         for cookie in &self.cookies {
             binding.header_adjoin(cookie);
         }
@@ -151,6 +337,110 @@ impl<'r> response::Responder<'r, 'static> for GenericJsonResponse {
     }
 }
 
+impl GenericJsonResponse {
+    fn ok<T: Serialize>(value: &T) -> Self {
+        match to_string(value) {
+            Ok(json) => GenericJsonResponse {
+                json,
+                status: Status::Ok,
+                cookies: Vec::new(),
+            },
+            Err(error) => ApiError::Internal(format!("Failed to serialize JSON: {}", error)).into(),
+        }
+    }
+
+    fn error(status: Status, error: &str) -> Self {
+        ApiError::Other(status, error.to_string()).into()
+    }
+}
+
+/// Builds the `username` and `id` cookies that identify a logged-in user,
+/// plus a fresh CSRF token so one issued before login cannot be reused.
+fn session_cookies(name: &str, uuid: &str) -> Vec<Cookie<'static>> {
+    let mut expiration_time = OffsetDateTime::now_utc();
+    expiration_time += Duration::weeks(52);
+    let mut cookies: Vec<Cookie<'static>> = [("username", name), ("id", uuid)]
+        .into_iter()
+        .map(|(cookie_name, value)| {
+            let mut cookie = Cookie::new(cookie_name, value.to_string());
+            cookie.set_expires(expiration_time);
+            cookie.set_path("/");
+            cookie.set_same_site(SameSite::Lax);
+            cookie
+        })
+        .collect();
+    cookies.push(csrf::token_cookie());
+    cookies
+}
+
+/// Cookies that make the browser forget the current session.
+fn expired_session_cookies() -> Vec<Cookie<'static>> {
+    ["username", "id"]
+        .into_iter()
+        .map(|cookie_name| {
+            let mut cookie = Cookie::new(cookie_name, "");
+            cookie.set_expires(OffsetDateTime::UNIX_EPOCH);
+            cookie.set_path("/");
+            cookie
+        })
+        .collect()
+}
+
+fn database_error(error: mongodb::error::Error) -> GenericJsonResponse {
+    ApiError::Database(error).into()
+}
+
+/// Reads every movie from a cursor. Documents that do not fit [`Movie`] are
+/// logged and skipped rather than failing the whole list.
+async fn collect_movies(mut cursor: mongodb::Cursor<Movie>) -> Result<Vec<MovieResponse>, ApiError> {
+    let mut movies = Vec::new();
+    while cursor.advance().await? {
+        let id = match cursor.current().get_object_id("_id") {
+            Ok(id) => id,
+            Err(error) => {
+                warn!(?error, "Skipping movie without an ObjectId");
+                continue;
+            }
+        };
+        match cursor.deserialize_current() {
+            Ok(movie) => movies.push(MovieResponse {
+                id: id.to_string(),
+                title: movie.title,
+                author: movie.author,
+                image_url: movie.image_url,
+                avg_rating: movie.avg_rating,
+                num_ratings: movie.num_ratings,
+            }),
+            Err(error) => warn!(?error, movie_id = %id, "Skipping malformed movie"),
+        }
+    }
+    Ok(movies)
+}
+
+/// Finds a user the way registration compares names: normalized and
+/// case-insensitively through `name_key`, falling back to the exact name for
+/// users registered before `name_key` existed.
+async fn user_by_name(name: &str, users: &Collection<User>) -> mongodb::error::Result<Option<User>> {
+    let name = validation::normalize_username(name);
+    if let Some(user) = users.find_one(doc! {"name_key": validation::username_key(&name)}, None).await? {
+        return Ok(Some(user));
+    }
+    users.find_one(doc! {"name": &name, "name_key": {"$exists": false}}, None).await
+}
+
+/// Loads the given movies, preserving the order of `ids` and dropping any
+/// that no longer exist.
+async fn movies_in_order(ids: &[ObjectId], server_data: &ServerData) -> Result<Vec<MovieResponse>, GenericJsonResponse> {
+    let query = doc! {"_id": {"$in": ids}};
+    let cursor = server_data.movies.find(query, None).await.map_err(database_error)?;
+    let mut found: HashMap<String, MovieResponse> = collect_movies(cursor)
+        .await?
+        .into_iter()
+        .map(|movie| (movie.id.clone(), movie))
+        .collect();
+    Ok(ids.iter().filter_map(|id| found.remove(&id.to_string())).collect())
+}
+
 #[get("/")]
 fn index_page() -> Redirect {
     Redirect::to("/movies")
@@ -191,80 +481,47 @@ async fn delete_movie_page() -> Option<NamedFile> {
         .ok()
 }
 
-#[get("/api/movies")]
-async fn get_movies(server_data: &State<ServerData>) -> GenericJsonResponse {
-    let mut movies: Vec<MovieResponse> = Vec::new();
-    match server_data.movies.find(None, None).await {
-        Ok(mut cursor) => {
-            while let Ok(true) = cursor.advance().await {
-                let obj_id = cursor.current().get_object_id("_id").unwrap().to_string();
-                let movie = cursor.deserialize_current().unwrap();
-                let movie = MovieResponse {
-                    id: obj_id,
-                    title: movie.title,
-                    author: movie.author,
-                    image_url: movie.image_url,
-                    avg_rating: movie.avg_rating,
-                    num_ratings: movie.num_ratings
-                };
-                movies.push(movie);
-            }
-        }
-        Err(error) => {
-            println!("{:?}", error);
-            let json = to_string(&json!({
-                "error": "Database error"
-            })).expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
-        }
-    }
+#[get("/forgot-password")]
+async fn forgot_password_page() -> Option<NamedFile> {
+    NamedFile::open(Path::new("static/").join("forgot-password.html"))
+        .await
+        .ok()
+}
 
-    let json = serde_json::to_string(&movies).expect("Failed to serialize to JSON");
-    GenericJsonResponse {
-        json,
-        status: Status::Ok,
-        cookies: Vec::new(),
-    }
+#[get("/reset-password")]
+async fn reset_password_page() -> Option<NamedFile> {
+    NamedFile::open(Path::new("static/").join("reset-password.html"))
+        .await
+        .ok()
 }
 
-#[get("/api/movies/<username>")]
-async fn get_movies_by_username(username: &str, cookies: &CookieJar<'_>, server_data: &State<ServerData>) -> GenericJsonResponse {
-    //let username = cookies.get("username").unwrap().value();
-    let uuid = cookies.get("id").unwrap().value();
-    let user_doc = doc!{"name": username, "uuid": uuid};
-    let user: User;
-    match server_data.users.find_one(user_doc.clone(), None).await {
-        Ok(u) => match u {
-            Some(u) => {
-                user = u;
-            },
-            None => {
-                let json = to_string(&json!({
-                    "error": "Unauthorized"
-                }))
-                .expect("Failed to serialize JSON");
-                return GenericJsonResponse {
-                    json,
-                    status: Status::Unauthorized,
-                    cookies: Vec::new(),
-                };
-            }
-        },
-        Err(_) => {
-            let json = to_string(&json!({
-                "error": "Database error"
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
-        }
+#[get("/lists/<_slug>")]
+async fn list_page(_slug: &str) -> Option<NamedFile> {
+    NamedFile::open(Path::new("static/").join("list.html"))
+        .await
+        .ok()
+}
+
+#[utoipa::path(tag = "movies", responses((status = 200, description = "Every movie", body = [MovieResponse])))]
+#[get("/movies")]
+async fn get_movies(server_data: &State<ServerData>) -> Result<GenericJsonResponse, ApiError> {
+    let cursor = server_data.movies.find(None, None).await?;
+    Ok(GenericJsonResponse::ok(&collect_movies(cursor).await?))
+}
+
+#[utoipa::path(
+    tag = "movies",
+    responses(
+        (status = 200, description = "The movies the caller added", body = [MovieResponse]),
+        (status = 403, description = "`username` is someone else", body = api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[get("/users/<username>/movies")]
+async fn get_movies_by_username(username: &str, auth: auth::Authenticated, server_data: &State<ServerData>) -> Result<GenericJsonResponse, ApiError> {
+    let user = auth.user;
+    if user.name != username {
+        return Err(ApiError::Forbidden("You can only list your own movies".to_string()));
     }
 
     let query = doc! {
@@ -272,241 +529,181 @@ async fn get_movies_by_username(username: &str, cookies: &CookieJar<'_>, server_
             "$in": user.created_movies.iter().collect::<Vec<_>>()
         }
     };
-    let mut movies: Vec<MovieResponse> = Vec::new();
-    match server_data.movies.find(query, None).await {
-        Ok(mut cursor) => {
-            while let Ok(true) = cursor.advance().await {
-                let obj_id = cursor.current().get_object_id("_id").unwrap().to_string();
-                let movie = cursor.deserialize_current().unwrap();
-                let movie = MovieResponse {
-                    id: obj_id,
-                    title: movie.title,
-                    author: movie.author,
-                    image_url: movie.image_url,
-                    avg_rating: movie.avg_rating,
-                    num_ratings: movie.num_ratings
-                };
-                movies.push(movie);
-            }
+    let cursor = server_data.movies.find(query, None).await?;
+    Ok(GenericJsonResponse::ok(&collect_movies(cursor).await?))
+}
+
+/// Logs in with a password. Answers with session cookies, tokens in JWT
+/// mode, or a pending token when the account has two-factor authentication.
+#[utoipa::path(
+    tag = "sessions",
+    request_body = UserLogin,
+    responses(
+        (status = 200, description = "Logged in, or a two-factor challenge started"),
+        (status = 401, description = "Wrong username or password", body = api::Problem),
+        (status = 429, description = "Too many attempts or the account is locked", body = api::Problem),
+    )
+)]
+#[post("/sessions", format = "json", data = "<user>")]
+async fn login(user: Json<UserLogin>, client_ip: Option<IpAddr>, server_data: &State<ServerData>, jwt: &State<jwt::Jwt>, _csrf: csrf::CsrfVerified) -> Result<GenericJsonResponse, ApiError> {
+    let limiter = &server_data.login_limiter;
+    limiter.check(&user.0.name, client_ip).await?;
+    let found = user_by_name(&user.0.name, &server_data.users).await?;
+    // The lock is checked before the password so a locked account does not
+    // reveal whether a guess was right.
+    if let Some(wait) = found.as_ref().and_then(rate_limit::locked_for) {
+        return Err(ApiError::RateLimited(wait));
+    }
+    let found = match found {
+        Some(found) if found.password_matches(&user.0.password) => {
+            limiter.record_success(&user.0.name).await;
+            found
         }
-        Err(error) => {
-            println!("{:?}", error);
-            let json = to_string(&json!({
-                "error": "Database error"
-            })).expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
+        found => {
+            limiter.record_failure(&user.0.name, client_ip).await;
+            let entry = match &found {
+                Some(found) => audit::AuditEntry::new(audit::AuditAction::LoginFailed, found, client_ip),
+                None => audit::AuditEntry::anonymous(audit::AuditAction::LoginFailed, &user.0.name, client_ip),
             };
+            audit::record(entry, server_data).await;
+            if let Some(found) = found {
+                rate_limit::record_account_failure(&found, server_data).await;
+            }
+            return Err(ApiError::InvalidCredentials);
         }
-    }
+    };
 
-    let json = serde_json::to_string(&movies).expect("Failed to serialize to JSON");
-    GenericJsonResponse {
-        json,
-        status: Status::Ok,
-        cookies: Vec::new(),
+    // Failed logins are only reset once the code is checked too, so guessing
+    // codes counts towards the lockout.
+    if found.two_factor.as_ref().is_some_and(two_factor::TwoFactor::enabled) {
+        return Ok(two_factor::begin_challenge(&found, server_data).await);
     }
+    rate_limit::record_account_success(&found, server_data).await;
+    Ok(complete_login(&found, client_ip, jwt, server_data).await)
 }
 
-#[post("/api/login", format = "json", data = "<user>")]
-async fn login(user: Json<UserLogin>, server_data: &State<ServerData>) -> GenericJsonResponse {
-    let uuid;
-    match server_data.users.find_one(user.0.clone(), None).await {
-        Ok(user) => match user {
-            Some(user) => {
-                uuid = user.uuid;
-            }
-            None => {
-                let json = to_string(&json!({
-                    "error": "Wrong username or password"
-                }))
-                .expect("Failed to serialize JSON");
-                return GenericJsonResponse {
-                    json,
-                    status: Status::Unauthorized,
-                    cookies: Vec::new(),
-                };
-            }
-        },
-        Err(_) => {
-            let json = to_string(&json!({
-                "error": "Database error"
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
-        }
+/// Logs in a user whose credentials have all been checked: session cookies
+/// and a redirect, or a token pair in JWT mode.
+async fn complete_login(user: &User, client_ip: Option<IpAddr>, jwt: &jwt::Jwt, server_data: &ServerData) -> GenericJsonResponse {
+    audit::record(audit::AuditEntry::new(audit::AuditAction::Login, user, client_ip), server_data).await;
+    if jwt.enabled() {
+        return match jwt::issue_tokens(user, None, jwt, server_data).await {
+            Ok(tokens) => GenericJsonResponse::ok(&tokens),
+            Err(response) => response,
+        };
     }
 
-    let json = to_string(&json!({
-        "redirectPath": "/movies"
-    }))
-    .expect("Failed to serialize JSON");
-
-    let mut expiration_time = OffsetDateTime::now_utc();
-    expiration_time += Duration::weeks(52);
-    let mut cookies = Vec::new();
-
-    let mut cookie = Cookie::new("username", user.0.name);
-    cookie.set_expires(expiration_time);
-    cookie.set_path("/");
-    cookies.push(cookie);
-
-    let mut cookie = Cookie::new("id", uuid);
-    cookie.set_expires(expiration_time);
-    cookie.set_path("/");
-    cookies.push(cookie);
-
-    return GenericJsonResponse {
-        json,
-        status: Status::Ok,
-        cookies,
-    };
+    let mut response = GenericJsonResponse::ok(&json!({"redirectPath": "/movies"}));
+    response.cookies = session_cookies(&user.name, &user.uuid);
+    response
 }
 
-#[post("/api/users", format = "json", data = "<user>")]
-async fn create_user(user: Json<UserLogin>, server_data: &State<ServerData>) -> GenericJsonResponse {
-    match server_data.users.find_one(doc!{"name": user.0.clone().name}, None).await {
-        Ok(user) => match user {
-            Some(_) => {
-                let json = to_string(&json!({
-                    "error": "Username alredy exists"
-                }))
-                .expect("Failed to serialize JSON");
-                return GenericJsonResponse {
-                    json,
-                    status: Status::Unauthorized,
-                    cookies: Vec::new(),
-                };
-            }
-            None => ()
-        },
-        Err(_) => {
-            let json = to_string(&json!({
-                "error": "Database error"
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
+#[utoipa::path(
+    tag = "users",
+    request_body = UserRegistration,
+    responses(
+        (status = 200, description = "Registered and logged in"),
+        (status = 409, description = "The username or email is taken", body = api::Problem),
+        (status = 422, description = "A field is invalid", body = api::Problem),
+    )
+)]
+#[post("/users", format = "json", data = "<user>")]
+async fn create_user(user: Json<UserRegistration>, client_ip: Option<IpAddr>, server_data: &State<ServerData>, _csrf: csrf::CsrfVerified) -> Result<GenericJsonResponse, ApiError> {
+    let name = validation::normalize_username(&user.0.name);
+    let name_key = validation::username_key(&name);
+    let email = user.0.email.as_deref().map(validation::normalize_email).filter(|email| !email.is_empty());
+    let mut errors = validation::FieldErrors::default();
+    errors.add("name", validation::validate_username(&name));
+    errors.add("password", validation::validate_password(&user.0.password, &name));
+    if let Some(email) = &email {
+        errors.add("email", validation::validate_email(email));
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    if let Some(email) = &email {
+        if server_data.users.find_one(doc! {"email": email}, None).await?.is_some() {
+            return Err(ApiError::EmailTaken);
         }
     }
 
-    let mut cookies = Vec::new();
-    let mut expiration_time = OffsetDateTime::now_utc();
-    expiration_time += Duration::weeks(52);
-    // username cookie
-    let mut cookie = Cookie::new("username", user.0.name.clone());
-    cookie.set_expires(expiration_time);
-    cookie.set_path("/");
-    cookies.push(cookie);
-    //id cookie
-    let uuid = Uuid::new_v4();
-    let mut cookie = Cookie::new("id", uuid.to_string());
-    cookie.set_expires(expiration_time);
-    cookie.set_path("/");
-    cookies.push(cookie.clone());
-
-    // uuid string to database
-    let uuid = cookie.to_string();
+    // Users registered before `name_key` existed only have `name` to compare against.
+    let existing = doc! {"$or": [{"name_key": &name_key}, {"name": &name}]};
+    if server_data.users.find_one(existing, None).await?.is_some() {
+        return Err(ApiError::UsernameTaken);
+    }
 
     // new user
-    let user = User {
-        name: user.0.name,
-        password: user.0.password,
-        uuid,
-        movie_ratings: Vec::new(),
-        created_movies: Vec::new()
-    };
-    let result = server_data.users.insert_one(user.clone(), None).await;
-    match result {
-        Ok(_) => {
-            let json = to_string(&json!({
-                "redirectPath": "/movies"
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::Ok,
-                cookies,
-            };
-        }
-        Err(error) => {
-            let error_message = format!("Failed to insert user: {}", error);
-            let json = to_string(&json!({
-                "error": error_message
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
+    let mut user = User::new(name, user.0.password);
+    user.email = email;
+    let cookies = session_cookies(&user.name, &user.uuid);
+    match server_data.users.insert_one(user.clone(), None).await {
+        Ok(result) => {
+            user.id = result.inserted_id.as_object_id();
+            server_data.telemetry.track_event(telemetry::Event::UserRegistered, &[("username", &user.name)]);
+            let entry = audit::AuditEntry::new(audit::AuditAction::UserRegistered, &user, client_ip)
+                .target(audit::TargetKind::User, user.id)
+                .changes(doc! {}, doc! {"name": &user.name, "email": &user.email});
+            audit::record(entry, server_data).await;
+            let mut response = GenericJsonResponse::ok(&json!({"redirectPath": "/movies"}));
+            response.cookies = cookies;
+            Ok(response)
         }
+        // Someone registered the same name between the lookup and the insert.
+        Err(error) if validation::is_duplicate_key(&error) => Err(ApiError::UsernameTaken),
+        Err(error) => Err(error.into()),
     }
 }
 
-#[post("/logout")]
-async fn logout(cookies: &CookieJar<'_>) -> GenericJsonResponse {
-    let user;
-    if cookies.get("uuid").is_some() && cookies.get("username").is_some() {
-        user = doc! {
-            "name": cookies.get("username").unwrap().value_trimmed(),
-            "uuid": cookies.get("uuid").unwrap().to_string(),
-        };
-        println!("User {:#?} logged out", user);
+/// Ends the session by expiring its cookies. In JWT mode, pass the refresh
+/// token to revoke its family as well; the access token stays valid until it
+/// expires.
+#[utoipa::path(
+    tag = "sessions",
+    request_body = Option<jwt::RefreshRequest>,
+    responses((status = 200, description = "Logged out"))
+)]
+#[delete("/sessions/current", data = "<request>")]
+async fn logout(request: Option<Json<jwt::RefreshRequest>>, cookies: &CookieJar<'_>, jwt: &State<jwt::Jwt>, server_data: &State<ServerData>, _csrf: csrf::CsrfVerified) -> GenericJsonResponse {
+    if let Some(username) = cookies.get("username") {
+        info!(user = username.value_trimmed(), "Logged out");
+    }
+    if let (true, Some(request)) = (jwt.enabled(), request) {
+        if let Err(response) = jwt::revoke_family(&request.refresh_token, server_data).await {
+            return response;
+        }
     }
 
-    return GenericJsonResponse {
+    GenericJsonResponse {
         json: "".to_string(),
         status: Status::Ok,
-        cookies: Vec::new(),
-    };
+        cookies: expired_session_cookies(),
+    }
 }
 
-#[post("/api/add-movie", format = "json", data = "<movie>")]
-async fn add_movie( movie: Json<MovieUploadRequest>, server_data: &State<ServerData>, cookies: &CookieJar<'_>,) -> GenericJsonResponse {
-    let username = cookies.get("username").unwrap().value();
-    let uuid = cookies.get("id").unwrap().value();
-    let user_doc = doc!{"name": username, "uuid": uuid};
-    let mut user: User;
-    match server_data.users.find_one(user_doc.clone(), None).await {
-        Ok(u) => match u {
-            Some(u) => {
-                user = u;
-            },
-            None => {
-                let json = to_string(&json!({
-                    "error": "Unauthorized"
-                }))
-                .expect("Failed to serialize JSON");
-                return GenericJsonResponse {
-                    json,
-                    status: Status::Unauthorized,
-                    cookies: Vec::new(),
-                };
-            }
-        },
-        Err(_) => {
-            let json = to_string(&json!({
-                "error": "Database error"
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
-        }
+#[utoipa::path(
+    tag = "movies",
+    request_body = MovieUploadRequest,
+    responses(
+        (status = 200, description = "Movie added"),
+        (status = 409, description = "A movie with this title and author exists", body = api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[post("/movies", format = "json", data = "<movie>")]
+async fn add_movie( movie: Json<MovieUploadRequest>, client_ip: Option<IpAddr>, server_data: &State<ServerData>, metrics: &State<metrics::Metrics>, auth: auth::Authenticated, _csrf: csrf::CsrfVerified) -> Result<GenericJsonResponse, ApiError> {
+    let mut user = auth.user;
+    metrics.record_upload(movie.image.len());
+
+    // Checked before uploading, so a duplicate leaves no blob behind.
+    let movie_doc = doc! {
+        "title": &movie.0.title,
+        "author": &movie.0.author,
+    };
+    if server_data.movies.find_one(movie_doc, None).await?.is_some() {
+        return Err(ApiError::MovieExists);
     }
-    
+
     let image_url = Uuid::new_v4();
     let image_url = format!("{}.png", image_url);
     let blob_client = server_data
@@ -517,38 +714,7 @@ async fn add_movie( movie: Json<MovieUploadRequest>, server_data: &State<ServerD
         .put_block_blob(movie.0.image)
         .content_type("application/octet-stream")
         .await
-        .unwrap();
-
-    let movie_doc = doc! {
-        "title": &movie.0.title,
-        "author": &movie.0.author,
-    };
-    match server_data.movies.find_one(movie_doc, None).await {
-        Ok(cursor) => {
-            if cursor.is_some() {
-                let json = to_string(&json!({
-                    "error": "Movie already exists"
-                }))
-                .expect("Failed to serialize JSON");
-                return GenericJsonResponse {
-                    json,
-                    status: Status::Conflict,
-                    cookies: Vec::new(),
-                };
-            }
-        }
-        Err(_) => {
-            let json = to_string(&json!({
-                "error": "Database error"
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
-        }
-    }
+        .map_err(ApiError::Storage)?;
 
     let movie = Movie {
         title: movie.0.title,
@@ -556,170 +722,317 @@ async fn add_movie( movie: Json<MovieUploadRequest>, server_data: &State<ServerD
         image_url,
         ..Default::default()
     };
-    let result = server_data.movies.insert_one(movie, None).await;
-    match result {
-        Ok(movie) => {
-            user.created_movies.push(movie.inserted_id.as_object_id().unwrap());
-            let update = doc! {
-                "$set": {
-                    "created_movies": &user.created_movies
-                }
-            };
-            let result = server_data.users.update_one(user_doc.clone(), update, None).await;
-            match result {
-                Ok(_) => (),
-                Err(error) => {
-                    let error_message = format!("Failed to insert user: {}", error);
-                    println!("{}", error_message);
-                }
-            }
-            let json = to_string(&json!({
-                "message": "Movie added"
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::Ok,
-                cookies: Vec::new(),
-            };
-        }
-        Err(error) => {
-            let error_message = format!("Failed to insert user: {}", error);
-            let json = to_string(&json!({
-                "error": error_message
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
+    let audit_doc = movie.audit_doc();
+    let inserted = server_data.movies.insert_one(&movie, None).await?;
+    let Some(movie_id) = inserted.inserted_id.as_object_id() else {
+        return Err(ApiError::Internal(format!("Inserted movie has id {:?}", inserted.inserted_id)));
+    };
+    let entry = audit::AuditEntry::new(audit::AuditAction::MovieCreated, &user, client_ip)
+        .target(audit::TargetKind::Movie, Some(movie_id))
+        .changes(doc! {}, audit_doc);
+    audit::record(entry, server_data).await;
+    server_data.events.publish(events::MovieEvent::Added(MovieResponse {
+        id: movie_id.to_string(),
+        title: movie.title,
+        author: movie.author,
+        image_url: movie.image_url,
+        avg_rating: movie.avg_rating,
+        num_ratings: movie.num_ratings,
+    }));
+    user.created_movies.push(movie_id);
+    let update = doc! {
+        "$set": {
+            "created_movies": &user.created_movies
         }
+    };
+    if let Err(error) = server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        error!(?error, "Failed to record created movie");
     }
+    feed::record_activity(feed::Activity::new(&user, feed::ActivityKind::MovieAdded, movie_id), server_data).await;
+    Ok(GenericJsonResponse::ok(&json!({"message": "Movie added"})))
 }
 
-#[delete("/api/movies")]
-async fn delete_movie(cookies: &CookieJar<'_>, server_data: &State<ServerData>) -> GenericJsonResponse {
-    let username = cookies.get("username").unwrap().value();
-    let uuid = cookies.get("id").unwrap().value();
-    let user_doc = doc!{"name": username, "uuid": uuid};
-    let mut user: User;
-    match server_data.users.find_one(user_doc.clone(), None).await {
-        Ok(u) => match u {
-            Some(u) => {
-                user = u;
-            },
-            None => {
-                let json = to_string(&json!({
-                    "error": "Unauthorized"
-                }))
-                .expect("Failed to serialize JSON");
-                return GenericJsonResponse {
-                    json,
-                    status: Status::Unauthorized,
-                    cookies: Vec::new(),
-                };
-            }
-        },
-        Err(_) => {
-            let json = to_string(&json!({
-                "error": "Database error"
-            }))
-            .expect("Failed to serialize JSON");
-            return GenericJsonResponse {
-                json,
-                status: Status::InternalServerError,
-                cookies: Vec::new(),
-            };
+/// Finds a movie the caller created, or 404 for anyone else's so other
+/// users cannot probe ids.
+async fn find_own_movie(id: &str, user: &User, server_data: &ServerData) -> Result<(ObjectId, Movie), ApiError> {
+    let not_found = || ApiError::NotFound("Movie not found".to_string());
+    let id = ObjectId::parse_str(id).map_err(|_| not_found())?;
+    if !user.created_movies.contains(&id) {
+        return Err(not_found());
+    }
+    let movie = server_data.movies.find_one(doc! {"_id": id}, None).await?.ok_or_else(not_found)?;
+    Ok((id, movie))
+}
+
+#[utoipa::path(
+    tag = "movies",
+    request_body = MovieUpdateRequest,
+    responses(
+        (status = 200, description = "Movie updated"),
+        (status = 404, description = "No movie with this id that the caller added", body = api::Problem),
+        (status = 409, description = "Another movie has this title and author", body = api::Problem),
+        (status = 422, description = "A field is empty", body = api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[patch("/movies/<id>", format = "json", data = "<request>")]
+async fn update_movie(id: &str, request: Json<MovieUpdateRequest>, client_ip: Option<IpAddr>, auth: auth::Authenticated, server_data: &State<ServerData>, _csrf: csrf::CsrfVerified) -> Result<GenericJsonResponse, ApiError> {
+    let user = auth.user;
+    let (id, before) = find_own_movie(id, &user, server_data).await?;
+    let mut errors = validation::FieldErrors::default();
+    let mut after = before.clone();
+    if let Some(title) = &request.title {
+        after.title = title.trim().to_string();
+        if after.title.is_empty() {
+            errors.add("title", vec!["Title cannot be empty".to_string()]);
+        }
+    }
+    if let Some(author) = &request.author {
+        after.author = author.trim().to_string();
+        if after.author.is_empty() {
+            errors.add("author", vec!["Author cannot be empty".to_string()]);
         }
     }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
 
-    //let query = doc! {
-    //    "_id": {
-    //        "$in": user.created_movies.iter().collect::<Vec<_>>()
-    //    }
-    //};
-    let mut movies: Vec<MovieResponse> = Vec::new();
-    //match server_data.movies.find(query, None).await {
-    //    Ok(mut cursor) => {
-    //        while let Ok(true) = cursor.advance().await {
-    //            let obj_id = cursor.current().get_object_id("_id").unwrap().to_string();
-    //            let movie = cursor.deserialize_current().unwrap();
-    //            let movie = MovieResponse {
-    //                id: obj_id,
-    //                title: movie.title,
-    //                author: movie.author,
-    //                image_url: movie.image_url,
-    //                avg_rating: movie.avg_rating,
-    //                num_ratings: movie.num_ratings
-    //            };
-    //            movies.push(movie);
-    //        }
-    //    }
-    //    Err(error) => {
-    //        println!("{:?}", error);
-    //        let json = to_string(&json!({
-    //            "error": "Database error"
-    //        })).expect("Failed to serialize JSON");
-    //        return GenericJsonResponse {
-    //            json,
-    //            status: Status::InternalServerError,
-    //            cookies: Vec::new(),
-    //        };
-    //    }
-    //}
-
-    let json = serde_json::to_string(&movies).expect("Failed to serialize to JSON");
-    GenericJsonResponse {
-        json,
-        status: Status::Ok,
-        cookies: Vec::new(),
+    let duplicate = doc! {"_id": {"$ne": id}, "title": &after.title, "author": &after.author};
+    if server_data.movies.find_one(duplicate, None).await?.is_some() {
+        return Err(ApiError::MovieExists);
     }
+    let update = doc! {"$set": {"title": &after.title, "author": &after.author}};
+    server_data.movies.update_one(doc! {"_id": id}, update, None).await?;
+    let entry = audit::AuditEntry::new(audit::AuditAction::MovieUpdated, &user, client_ip)
+        .target(audit::TargetKind::Movie, Some(id))
+        .changes(before.audit_doc(), after.audit_doc());
+    audit::record(entry, server_data).await;
+    Ok(GenericJsonResponse::ok(&json!({"message": "Movie updated"})))
 }
 
-#[get("/api/thumbnail/<image_url>")]
-async fn get_thumbnail(image_url: &str, server_data: &State<ServerData>) -> GenericJsonResponse {
-    if image_url == "" {
-        return GenericJsonResponse {
-            json: "".to_string(),
-            status: Status::NotFound,
-            cookies: Vec::new(),
-        };
+/// Changes a movie's average by one rating in a single update, so that
+/// concurrent changes all count. `added` is 1 for a new rating, 0 for a
+/// changed one and -1 for a removed one; `delta` is what the sum of ratings
+/// changes by.
+fn rating_update(added: i32, delta: f64) -> Vec<Document> {
+    let num_ratings = doc! {"$add": ["$num_ratings", added]};
+    let total = doc! {"$add": [{"$multiply": ["$avg_rating", "$num_ratings"]}, delta]};
+    vec![doc! {"$set": {
+        "num_ratings": &num_ratings,
+        "avg_rating": {"$cond": [{"$gt": [&num_ratings, 0]}, {"$divide": [total, &num_ratings]}, 0.0]},
+    }}]
+}
+
+/// Moves the caller's rating of `movie_id` to the end of `movie_ratings`,
+/// replacing any earlier one. Returns the earlier one, read in the same
+/// update so that concurrent ratings by the same user see each other.
+async fn replace_rating(user_id: Option<ObjectId>, movie_id: ObjectId, rating: f64, users: &Collection<User>) -> Result<Option<f64>, ApiError> {
+    let others = doc! {"$filter": {
+        "input": "$movie_ratings",
+        "cond": {"$ne": [{"$arrayElemAt": ["$$this", 0]}, movie_id]},
+    }};
+    let update = vec![doc! {"$set": {"movie_ratings": {"$concatArrays": [others, [[movie_id, rating]]]}}}];
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::Before).build();
+    let before = users.find_one_and_update(doc! {"_id": user_id}, update, options).await?;
+    let before = before.ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+    Ok(before.movie_ratings.iter().find(|&&(id, _)| id == movie_id).map(|&(_, previous)| previous))
+}
+
+/// Rates a movie, replacing the caller's earlier rating of it, and posts the
+/// review if there is one. Both show up in followers' feeds.
+#[utoipa::path(
+    tag = "movies",
+    request_body = RatingRequest,
+    responses(
+        (status = 200, description = "Rated; the body has the movie's new average"),
+        (status = 404, description = "No movie with this id", body = api::Problem),
+        (status = 422, description = "The rating is not between 1 and 5, or the review is too long", body = api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[put("/movies/<id>/rating", format = "json", data = "<request>")]
+async fn rate_movie(id: &str, request: Json<RatingRequest>, auth: auth::Authenticated, server_data: &State<ServerData>, _csrf: csrf::CsrfVerified) -> Result<GenericJsonResponse, ApiError> {
+    let user = auth.user;
+    let not_found = || ApiError::NotFound("Movie not found".to_string());
+    let movie_id = ObjectId::parse_str(id).map_err(|_| not_found())?;
+    let rating = request.rating;
+    let review = request.review.as_deref().map(str::trim).filter(|review| !review.is_empty());
+    let mut errors = validation::FieldErrors::default();
+    if !(1.0..=5.0).contains(&rating) {
+        errors.add("rating", vec!["Rating must be between 1 and 5".to_string()]);
+    }
+    if review.is_some_and(|review| review.chars().count() > MAX_REVIEW_LEN) {
+        errors.add("review", vec![format!("Review must be at most {} characters", MAX_REVIEW_LEN)]);
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+    if server_data.movies.find_one(doc! {"_id": movie_id}, None).await?.is_none() {
+        return Err(not_found());
+    }
+
+    let update = match replace_rating(user.id, movie_id, rating, &server_data.users).await? {
+        Some(previous) => rating_update(0, rating - previous),
+        None => rating_update(1, rating),
+    };
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let movie = server_data.movies.find_one_and_update(doc! {"_id": movie_id}, update, options).await?.ok_or_else(not_found)?;
+    server_data.events.publish(events::MovieEvent::RatingChanged { movie_id, avg_rating: movie.avg_rating, num_ratings: movie.num_ratings });
+
+    let activity = |kind, review: Option<&str>| {
+        feed::Activity::new(&user, kind, movie_id).map(|mut activity| {
+            activity.rating = Some(rating);
+            activity.review = review.map(str::to_string);
+            activity
+        })
+    };
+    feed::record_activity(activity(feed::ActivityKind::MovieRated, None), server_data).await;
+    if review.is_some() {
+        feed::record_activity(activity(feed::ActivityKind::ReviewPosted, review), server_data).await;
+    }
+    Ok(GenericJsonResponse::ok(&json!({"avg_rating": movie.avg_rating, "num_ratings": movie.num_ratings})))
+}
+
+/// Deletes a movie the caller created, with its image, list entries, feed
+/// events and everyone's ratings of it.
+#[utoipa::path(
+    tag = "movies",
+    responses(
+        (status = 200, description = "Movie deleted"),
+        (status = 404, description = "No movie with this id that the caller added", body = api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[delete("/movies/<id>")]
+async fn delete_own_movie(id: &str, client_ip: Option<IpAddr>, auth: auth::Authenticated, server_data: &State<ServerData>, _csrf: csrf::CsrfVerified) -> Result<GenericJsonResponse, ApiError> {
+    let user = auth.user;
+    let (id, movie) = find_own_movie(id, &user, server_data).await?;
+    account::delete_movies(&[id], server_data).await?;
+    let update = doc! {"$pull": {"created_movies": id}};
+    if let Err(error) = server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        error!(?error, "Failed to forget deleted movie");
+    }
+    let entry = audit::AuditEntry::new(audit::AuditAction::MovieDeleted, &user, client_ip)
+        .target(audit::TargetKind::Movie, Some(id))
+        .changes(movie.audit_doc(), doc! {});
+    audit::record(entry, server_data).await;
+    Ok(GenericJsonResponse::ok(&json!({"message": "Movie deleted"})))
+}
+
+/// The image of a movie, as a JSON array of bytes.
+#[utoipa::path(
+    tag = "movies",
+    responses(
+        (status = 200, description = "The image", body = Vec<u8>),
+        (status = 404, description = "No such image", body = api::Problem),
+    )
+)]
+#[get("/thumbnails/<image_url>")]
+async fn get_thumbnail(image_url: &str, server_data: &State<ServerData>) -> Result<GenericJsonResponse, ApiError> {
+    if image_url.is_empty() {
+        return Err(ApiError::NotFound("Thumbnail not found".to_string()));
     }
     let blob_client = server_data
         .blob_client_builder
         .clone()
         .blob_client(&server_data.container_name, image_url);
-    let response = blob_client.get_content().await.unwrap();
-    let json = to_string(&json!(response)).expect("Failed to serialize JSON");
-    return GenericJsonResponse {
-        json,
-        status: Status::Ok,
-        cookies: Vec::new(),
-    };
+    match blob_client.get_content().await {
+        Ok(content) => Ok(GenericJsonResponse::ok(&content)),
+        Err(error) if error::is_not_found(&error) => Err(ApiError::NotFound("Thumbnail not found".to_string())),
+        Err(error) => Err(ApiError::Storage(error)),
+    }
 }
 
 async fn setup_rocket() -> Rocket<Build> {
-    let server_data = ServerData::new().await;
-    rocket::build()
+    dotenv().ok();
+    let figment = config::figment();
+    let app_config = config::AppConfig::from_figment(&figment).unwrap_or_else(|error| panic!("{}", error));
+    let tracer = otel::tracer(&app_config.tracing).unwrap_or_else(|error| panic!("Invalid tracing configuration: {}", error));
+    logging::init(&app_config.logging, tracer).unwrap_or_else(|error| panic!("{}", error));
+    info!(profile = %app_config.profile, config = ?app_config, "Configuration loaded");
+    let metrics = metrics::Metrics::new();
+    let telemetry: telemetry::Telemetry = Arc::new(telemetry::Fanout(vec![
+        telemetry::from_config(app_config.telemetry.instrumentation_key.as_ref().map(config::Secret::expose)),
+        Arc::new(metrics.clone()),
+    ]));
+    let server_data = ServerData::new(&app_config, telemetry.clone()).await;
+    let graphiql = if app_config.profile == "dev" { routes![graphql::graphiql] } else { Vec::new() };
+    rocket::custom(figment)
         .manage(server_data)
-        .mount("/", routes![
+        .manage(metrics)
+        .manage(graphql::schema())
+        .attach(api::LegacyAliases)
+        .attach(logging::RequestIdFairing)
+        .attach(otel::TraceShutdown)
+        .attach(telemetry::TelemetryFairing(telemetry))
+        .attach(jwt::JwtFairing(app_config.jwt_keys.clone()))
+        .attach(oidc::OidcFairing(app_config.oidc_client_secrets.clone()))
+        .attach(throttle::ThrottleFairing)
+        .attach(csrf::CsrfFairing)
+        .mount("/", logging::traced(routes![
                index_page,
                movies_page,
                login_page,
                register_page,
                add_movie_page,
                delete_movie_page,
+               forgot_password_page,
+               reset_password_page,
+               list_page,
+               oidc::begin_login,
+               oidc::callback,
+               throttle::rate_limited,
+               health::live,
+               health::ready,
+               metrics::metrics
+        ]))
+        .mount(api::BASE, logging::traced(routes![
                get_movies,
+               events::movie_events,
                get_thumbnail,
                login,
+               two_factor::complete_challenge,
+               jwt::refresh,
+               password_reset::forgot_password,
+               password_reset::reset_password,
                create_user,
                logout,
                add_movie,
                get_movies_by_username,
-               delete_movie
-        ])
+               update_movie,
+               rate_movie,
+               delete_own_movie,
+               lists::create_list,
+               lists::get_public_lists,
+               lists::get_my_lists,
+               lists::get_list,
+               lists::update_list,
+               lists::delete_list,
+               lists::add_list_movie,
+               lists::remove_list_movie,
+               lists::reorder_list,
+               profiles::get_profile,
+               profiles::update_privacy,
+               feed::follow_user,
+               feed::unfollow_user,
+               feed::get_feed,
+               account::change_password,
+               account::change_username,
+               account::change_email,
+               account::delete_account,
+               two_factor::enroll,
+               two_factor::verify_enrollment,
+               two_factor::disable,
+               tokens::create_token,
+               tokens::get_tokens,
+               tokens::revoke_token,
+               audit::get_audit_log
+        ]))
+        .mount("/", logging::traced(api::docs()))
+        .mount("/", logging::traced(routes![graphql::graphql_query, graphql::graphql_request]))
+        .mount("/", logging::traced(graphiql))
         .mount("/", FileServer::from("static"))
+        .register("/", catchers![error::bad_request, auth::unauthorized, auth::forbidden, error::not_found, error::unprocessable_entity, error::internal_error])
 }
 
 #[rocket::main]
@@ -730,20 +1043,51 @@ async fn main() {
 
 #[cfg(test)]
 mod tests {
-    use super::setup_rocket;
+    use super::{rating_update, setup_rocket, User};
+    use mongodb::bson::doc;
     use rocket::{
         local::asynchronous::Client,
         http::{
             Status,
-            ContentType
+            ContentType,
+            Cookie,
+            Header
         },
         async_test
     };
+    use rocket::http::Method;
+    use proptest::prelude::*;
     use std::{
         path::Path,
         fs::read
     };
     use serde_json::json;
+    use cookie::time::OffsetDateTime;
+
+    #[test]
+    fn test_password_matches() {
+        let mut user = User::new("alice".to_string(), "correct horse".to_string());
+        assert!(user.password_matches("correct horse"));
+        assert!(!user.password_matches("wrong"));
+        // Accounts created through an identity provider have a random password
+        // nobody knows, but it must not work even if it were guessed.
+        user.password_unset = true;
+        assert!(!user.password_matches("correct horse"));
+    }
+
+    #[test]
+    fn test_rating_update() {
+        let update = rating_update(-1, -5.0);
+        let set = update[0].get_document("$set").unwrap();
+        assert_eq!(set.get_document("num_ratings").unwrap(), &doc! {"$add": ["$num_ratings", -1]});
+        let average = set.get_document("avg_rating").unwrap().get_array("$cond").unwrap();
+        // Every field is computed from the document as it was, so the old
+        // count multiplies the old average.
+        assert_eq!(
+            average[1].as_document().unwrap(),
+            &doc! {"$divide": [{"$add": [{"$multiply": ["$avg_rating", "$num_ratings"]}, -5.0]}, {"$add": ["$num_ratings", -1]}]}
+        );
+    }
 
     #[async_test]
     async fn test_index_page_redirect() {
@@ -828,6 +1172,8 @@ mod tests {
         let response = client
             .post("/api/login")
             .header(ContentType::JSON)
+            .cookie(Cookie::new("csrf_token", "test-token"))
+            .header(Header::new("X-CSRF-Token", "test-token"))
             .body(payload)
             .dispatch()
             .await;
@@ -837,7 +1183,26 @@ mod tests {
     }
     // test post users, then login, then delete
 
-    // test logout
+    #[async_test]
+    async fn test_logout_expires_cookies() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/login").dispatch().await;
+        let token = response.cookies().get("csrf_token").map(|cookie| cookie.value().to_string()).expect("CSRF cookie");
+        let response = client
+            .delete("/api/v1/sessions/current")
+            .cookie(Cookie::new("id", "65a1f0c2-e4b0-a1b2-c3d4-e5f6a1b2c3d4"))
+            .header(Header::new("X-CSRF-Token", token))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let expired = response.cookies().get("id").and_then(|cookie| cookie.expires_datetime()).expect("expired id cookie");
+        assert!(expired < OffsetDateTime::now_utc());
+        assert!(client.cookies().get("id").is_none());
+    }
 
     #[async_test]
     async fn test_add_movie_page() {
@@ -856,4 +1221,404 @@ mod tests {
 
     // test add movie
     // test get thumbnail
+
+    #[async_test]
+    async fn test_create_user_validation() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let payload = json!({
+            "name": "bad;name",
+            "password": "f",
+            "email": "not-an-email",
+        }).to_string();
+        let response = client
+            .post("/api/users")
+            .header(ContentType::JSON)
+            .cookie(Cookie::new("csrf_token", "test-token"))
+            .header(Header::new("X-CSRF-Token", "test-token"))
+            .body(payload)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert!(body["fields"]["name"].is_array());
+        assert!(body["fields"]["password"].is_array());
+        assert!(body["fields"]["email"].is_array());
+    }
+
+    #[async_test]
+    async fn test_request_id() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/health/live").header(Header::new("X-Request-Id", "abc-123")).dispatch().await;
+        assert_eq!(response.headers().get_one("X-Request-Id"), Some("abc-123"));
+
+        let response = client.get("/health/live").header(Header::new("X-Request-Id", "not valid")).dispatch().await;
+        let id = response.headers().get_one("X-Request-Id").expect("request id assigned");
+        assert_ne!(id, "not valid");
+        assert!(uuid::Uuid::parse_str(id).is_ok());
+    }
+
+    #[async_test]
+    async fn test_metrics() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        client.get("/health/live").dispatch().await;
+        let response = client.get("/metrics").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#"cinema_score_http_requests_total{method="GET",route="/health/live",status="200"} 1"#));
+        assert!(body.contains("cinema_score_movies"));
+    }
+
+    #[async_test]
+    async fn test_health() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/health/live").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // Neither the database nor the storage account is reachable here.
+        let response = client.get("/health/ready").dispatch().await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["status"], "unavailable");
+        assert_eq!(body["checks"]["database"]["status"], "down");
+        assert!(body["checks"]["storage"]["latency_ms"].is_u64());
+    }
+
+    #[async_test]
+    async fn test_error_responses() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/api/no-such-route").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::new("application", "problem+json")));
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["code"], "not_found");
+
+        let response = client
+            .post("/api/users")
+            .header(ContentType::JSON)
+            .cookie(Cookie::new("csrf_token", "test-token"))
+            .header(Header::new("X-CSRF-Token", "test-token"))
+            .body(json!({"name": 42}).to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["code"], "unprocessable_entity");
+    }
+
+    #[async_test]
+    async fn test_forgot_password_validation() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client
+            .post("/api/password/forgot")
+            .header(ContentType::JSON)
+            .cookie(Cookie::new("csrf_token", "test-token"))
+            .header(Header::new("X-CSRF-Token", "test-token"))
+            .body(json!({"email": "nobody"}).to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body: serde_json::Value = response.into_json().await.unwrap();
+        assert!(body["fields"]["email"].is_array());
+    }
+
+    #[async_test]
+    async fn test_csrf_token_required() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/login").dispatch().await;
+        let token = response.cookies().get("csrf_token").map(|cookie| cookie.value().to_string());
+        assert!(token.is_some());
+
+        let payload = json!({"name": "mariusz", "password": "f"}).to_string();
+        let response = client
+            .post("/api/login")
+            .header(ContentType::JSON)
+            .body(&payload)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(response.content_type(), Some(ContentType::new("application", "problem+json")));
+
+        let response = client
+            .post("/api/login")
+            .header(ContentType::JSON)
+            .header(Header::new("X-CSRF-Token", "forged"))
+            .body(&payload)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[async_test]
+    async fn test_rating_requires_login() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/login").dispatch().await;
+        let token = response.cookies().get("csrf_token").map(|cookie| cookie.value().to_string()).expect("CSRF cookie");
+        let response = client
+            .put("/api/v1/movies/65a1f0c2e4b0a1b2c3d4e5f6/rating")
+            .header(ContentType::JSON)
+            .header(Header::new("X-CSRF-Token", token))
+            .body(json!({"rating": 4}).to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[async_test]
+    async fn test_feed_requires_login() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/api/feed").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[async_test]
+    async fn test_legacy_paths() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/api/v1/feed").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.headers().get_one("Deprecation"), None);
+
+        let response = client.get("/api/feed?page=2").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(response.headers().get_one("Deprecation").is_some_and(|date| date.starts_with('@')));
+        assert_eq!(response.headers().get_one("Link"), Some(r#"</api/v1/feed>; rel="successor-version""#));
+
+        let response = client.get("/api/no-such-route").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.headers().get_one("Deprecation"), None);
+    }
+
+    #[async_test]
+    async fn test_openapi() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/api/v1/openapi.json").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value = response.into_json().await.expect("OpenAPI document");
+        assert!(body["openapi"].as_str().is_some_and(|version| version.starts_with("3.")));
+        assert_eq!(body["servers"][0]["url"], "/api/v1");
+        assert!(body["paths"]["/movies"]["post"].is_object());
+        assert!(body["paths"]["/movies/{id}/rating"]["put"].is_object());
+
+        let response = client.get("/api/v1/docs/").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+    }
+
+    #[async_test]
+    async fn test_graphql() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client
+            .post("/graphql")
+            .header(ContentType::JSON)
+            .body(r#"{"query": "{ me { name } }"}"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value = response.into_json().await.expect("GraphQL response");
+        assert_eq!(body, json!({"data": {"me": null}}));
+
+        let response = client.get("/graphql?query=%7B%20me%20%7B%20name%20%7D%20%7D").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // GraphiQL is only served in the `dev` profile.
+        let response = client.get("/graphiql").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[async_test]
+    async fn test_movie_events() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/api/v1/events?movie=65a1f0c2e4b0a1b2c3d4e5f6").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::EventStream));
+
+        let response = client.get("/api/v1/events?movie=not-an-id").dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[async_test]
+    async fn test_audit_log_requires_login() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/api/admin/audit?action=login_failed").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[async_test]
+    async fn test_api_token_scheme() {
+        let rocket = setup_rocket().await;
+        let client = Client::tracked(rocket)
+            .await
+            .expect("valid rocket instance");
+
+        let response = client
+            .get("/api/feed")
+            .header(Header::new("Authorization", "Basic dXNlcjpwYXNz"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        let body: serde_json::Value = response.into_json().await.expect("JSON error body");
+        assert_eq!(body["error"], "Authorization header must use the Bearer scheme");
+    }
+
+    #[async_test]
+    async fn test_api_rate_limit() {
+        let rocket = setup_rocket().await;
+        let bucket = json!({"capacity": 1, "per_minute": 1});
+        let figment = rocket.figment().clone().merge(("rate_limit.groups", json!([{
+            "name": "feed",
+            "prefixes": ["/api/v1/feed"],
+            "anonymous": bucket,
+            "authenticated": bucket,
+        }])));
+        let client = Client::tracked(rocket.configure(figment))
+            .await
+            .expect("valid rocket instance");
+
+        let response = client.get("/api/v1/feed").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.headers().get_one("RateLimit-Limit"), Some("1"));
+        assert_eq!(response.headers().get_one("RateLimit-Remaining"), Some("0"));
+
+        let response = client.get("/api/v1/feed").dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("60"));
+
+        // A client-supplied address does not get a fresh bucket.
+        let response = client.get("/api/v1/feed").header(Header::new("X-Real-IP", "203.0.113.9")).dispatch().await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+
+        let response = client.get("/movies").dispatch().await;
+        assert_eq!(response.headers().get_one("RateLimit-Limit"), None);
+    }
+
+    fn request_paths() -> impl Strategy<Value = String> {
+        let segment = "[a-zA-Z0-9._~-]{0,16}";
+        prop_oneof![
+            segment.prop_map(|s| format!("/api/movies/{}", s)),
+            segment.prop_map(|s| format!("/api/lists/{}", s)),
+            (segment, segment).prop_map(|(a, b)| format!("/api/lists/{}/movies/{}", a, b)),
+            segment.prop_map(|s| format!("/api/users/{}/follow", s)),
+            segment.prop_map(|s| format!("/api/users/{}", s)),
+            segment.prop_map(|s| format!("/api/tokens/{}", s)),
+            prop::sample::select(vec![
+                "/api/movies", "/api/add-movie", "/api/login", "/api/login/2fa", "/api/users", "/api/lists",
+                "/api/lists/mine", "/api/feed", "/api/tokens", "/api/auth/refresh", "/api/password/forgot",
+                "/api/password/reset", "/api/users/me/password", "/api/users/me/name", "/api/users/me/email",
+                "/api/users/me", "/api/users/me/2fa", "/api/users/me/2fa/verify", "/api/users/me/privacy", "/logout",
+            ])
+            .prop_map(String::from),
+            "/[a-z/]{0,24}",
+        ]
+    }
+
+    fn json_values() -> impl Strategy<Value = serde_json::Value> {
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            any::<f64>().prop_map(serde_json::Value::from),
+            "\\PC{0,24}".prop_map(serde_json::Value::from),
+        ];
+        leaf.prop_recursive(2, 8, 4, |inner| prop::collection::vec(inner, 0..4).prop_map(serde_json::Value::from))
+    }
+
+    fn request_bodies() -> impl Strategy<Value = String> {
+        let fields = prop::sample::select(vec![
+            "name", "password", "email", "title", "author", "image", "code", "token", "pending_token", "new_password",
+            "old_password", "refresh_token", "movie_id", "rating", "created_movies", "public", "order",
+        ]);
+        prop_oneof![
+            prop::collection::btree_map(fields, json_values(), 0..6).prop_map(|map| json!(map).to_string()),
+            "\\PC{0,64}",
+        ]
+    }
+
+    /// Random requests must never make a handler panic. Rocket answers a
+    /// panic through the 500 catcher, whose code no handled error uses.
+    /// Thumbnails are left out: without blob storage each one waits out the
+    /// storage client's retries.
+    #[test]
+    fn test_no_panics() {
+        let runtime = rocket::tokio::runtime::Runtime::new().expect("runtime");
+        let client = runtime.block_on(async { Client::tracked(setup_rocket().await).await.expect("valid rocket instance") });
+        let methods = prop::sample::select(vec![Method::Get, Method::Post, Method::Put, Method::Delete]);
+        let config = ProptestConfig { cases: 48, failure_persistence: None, ..ProptestConfig::default() };
+        proptest!(config, |(method in methods, path in request_paths(), body in request_bodies(), bearer in any::<bool>())| {
+            let (status, code) = runtime.block_on(async {
+                let mut request = client
+                    .req(method, path.as_str())
+                    .header(ContentType::JSON)
+                    .cookie(Cookie::new("csrf_token", "test-token"))
+                    .header(Header::new("X-CSRF-Token", "test-token"))
+                    .body(&body);
+                if bearer {
+                    request = request.header(Header::new("Authorization", "Bearer cs_not-a-real-token"));
+                }
+                let response = request.dispatch().await;
+                let status = response.status();
+                let body: Option<serde_json::Value> = response.into_json().await;
+                (status, body.and_then(|body| body["code"].as_str().map(String::from)))
+            });
+            prop_assert!(
+                !(status == Status::InternalServerError && code.as_deref() == Some("internal_server_error")),
+                "{} {} panicked with body {}", method, path, body
+            );
+        });
+    }
 }
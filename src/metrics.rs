@@ -0,0 +1,236 @@
+use crate::error::ApiError;
+use crate::telemetry::{Event, TelemetrySink};
+use crate::ServerData;
+use mongodb::bson::{doc, Bson};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use rocket::http::ContentType;
+use rocket::{get, State};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long the counts behind the gauges may take before the previous values
+/// are served instead.
+const COUNT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Scrapes within this long of the last count reuse its values, so frequent
+/// or parallel scrapers do not each query the database.
+const COUNT_TTL: Duration = Duration::from_secs(15);
+
+/// Prometheus metrics, fed through the same hooks as telemetry and served at
+/// `/metrics`. Clones share the same series.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    dependency_duration: HistogramVec,
+    dependency_errors: IntCounterVec,
+    movies: IntGauge,
+    users: IntGauge,
+    ratings: IntGauge,
+    upload_bytes: IntCounter,
+    /// When the gauges were last counted.
+    counted_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("cinema_score".to_string()), None).expect("valid metrics prefix");
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time to handle an HTTP request"),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let dependency_duration = HistogramVec::new(
+            HistogramOpts::new("dependency_duration_seconds", "Time taken by database and blob storage calls"),
+            &["dependency", "operation"],
+        )
+        .expect("valid metric");
+        let dependency_errors = IntCounterVec::new(
+            Opts::new("dependency_errors_total", "Failed database and blob storage calls"),
+            &["dependency", "operation"],
+        )
+        .expect("valid metric");
+        let movies = IntGauge::new("movies", "Movies in the database").expect("valid metric");
+        let users = IntGauge::new("users", "Registered users").expect("valid metric");
+        let ratings = IntGauge::new("ratings", "Ratings given across all movies").expect("valid metric");
+        let upload_bytes = IntCounter::new("upload_bytes_total", "Bytes of uploaded images").expect("valid metric");
+
+        let metrics = Metrics {
+            registry,
+            requests,
+            request_duration,
+            dependency_duration,
+            dependency_errors,
+            movies,
+            users,
+            ratings,
+            upload_bytes,
+            counted_at: Arc::new(Mutex::new(None)),
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 8] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.request_duration.clone()),
+            Box::new(metrics.dependency_duration.clone()),
+            Box::new(metrics.dependency_errors.clone()),
+            Box::new(metrics.movies.clone()),
+            Box::new(metrics.users.clone()),
+            Box::new(metrics.ratings.clone()),
+            Box::new(metrics.upload_bytes.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("metric registered once");
+        }
+        metrics
+    }
+
+    pub fn record_upload(&self, bytes: usize) {
+        self.upload_bytes.inc_by(bytes as u64);
+    }
+
+    /// Whether the gauges are due for a count. Claims the count if so, so
+    /// only one of several scrapes at the same time makes it.
+    fn claim_count(&self, now: Instant) -> bool {
+        let mut counted_at = self.counted_at.lock().unwrap_or_else(|error| error.into_inner());
+        if counted_at.is_some_and(|counted_at| now.saturating_duration_since(counted_at) < COUNT_TTL) {
+            return false;
+        }
+        *counted_at = Some(now);
+        true
+    }
+
+    /// Counts movies, users and ratings for the gauges, at most once per
+    /// `COUNT_TTL`. A count that fails leaves its gauge as it was.
+    async fn refresh_counts(&self, server_data: &ServerData) {
+        if !self.claim_count(Instant::now()) {
+            return;
+        }
+        let ratings = async {
+            let pipeline = [doc! {"$group": {"_id": null, "ratings": {"$sum": "$num_ratings"}}}];
+            let mut cursor = server_data.movies.aggregate(pipeline, None).await?;
+            let total = match cursor.advance().await? {
+                true => match cursor.deserialize_current()?.get("ratings") {
+                    Some(Bson::Int32(total)) => i64::from(*total),
+                    Some(Bson::Int64(total)) => *total,
+                    _ => 0,
+                },
+                false => 0,
+            };
+            Ok::<_, mongodb::error::Error>(total)
+        };
+        let (movies, users, ratings) = tokio::join!(
+            count("movies", server_data.movies.estimated_document_count(None)),
+            count("users", server_data.users.estimated_document_count(None)),
+            count("ratings", ratings),
+        );
+        if let Some(movies) = movies {
+            self.movies.set(movies as i64);
+        }
+        if let Some(users) = users {
+            self.users.set(users as i64);
+        }
+        if let Some(ratings) = ratings {
+            self.ratings.set(ratings);
+        }
+    }
+
+    fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|error| prometheus::Error::Msg(error.to_string()))
+    }
+}
+
+async fn count<T>(name: &str, query: impl Future<Output = mongodb::error::Result<T>>) -> Option<T> {
+    match tokio::time::timeout(COUNT_TIMEOUT, query).await {
+        Ok(Ok(count)) => Some(count),
+        Ok(Err(error)) => {
+            warn!(?error, gauge = name, "Failed to count for metrics");
+            None
+        }
+        Err(_) => {
+            warn!(gauge = name, "Counting for metrics timed out");
+            None
+        }
+    }
+}
+
+/// Turns a dependency call into bounded labels: `mongodb` with the command
+/// and collection, or `blob` with the HTTP method, since blob paths are
+/// unique per image.
+fn dependency_labels<'a>(kind: &str, name: &'a str) -> (&'static str, &'a str) {
+    match kind {
+        "MongoDB" => ("mongodb", name),
+        _ => ("blob", name.split(' ').next().unwrap_or(name)),
+    }
+}
+
+impl TelemetrySink for Metrics {
+    fn track_request(&self, method: &str, _: &str, route: Option<&str>, status: u16, duration: Duration) {
+        let status = status.to_string();
+        let labels = [method, route.unwrap_or("unmatched"), status.as_str()];
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration.with_label_values(&labels).observe(duration.as_secs_f64());
+    }
+
+    fn track_dependency(&self, kind: &str, name: &str, _: &str, duration: Duration, success: bool) {
+        let (dependency, operation) = dependency_labels(kind, name);
+        self.dependency_duration.with_label_values(&[dependency, operation]).observe(duration.as_secs_f64());
+        if !success {
+            self.dependency_errors.with_label_values(&[dependency, operation]).inc();
+        }
+    }
+
+    fn track_exception(&self, _: &str, _: &str, _: Option<&str>, _: u16) {}
+
+    fn track_event(&self, _: Event, _: &[(&str, &str)]) {}
+}
+
+/// The text exposition format, version 0.0.4.
+#[get("/metrics")]
+pub async fn metrics(metrics: &State<Metrics>, server_data: &State<ServerData>) -> Result<(ContentType, String), ApiError> {
+    metrics.refresh_counts(server_data).await;
+    let body = metrics.encode().map_err(|error| ApiError::Internal(format!("Failed to encode metrics: {}", error)))?;
+    Ok((ContentType::new("text", "plain").with_params(("version", "0.0.4")), body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dependency_labels, Metrics, COUNT_TTL};
+    use crate::telemetry::TelemetrySink;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_claim_count() {
+        let metrics = Metrics::new();
+        let now = Instant::now();
+        assert!(metrics.claim_count(now));
+        assert!(!metrics.clone().claim_count(now + Duration::from_secs(1)));
+        assert!(metrics.claim_count(now + COUNT_TTL));
+    }
+
+    #[test]
+    fn test_dependency_labels() {
+        assert_eq!(dependency_labels("MongoDB", "find movies"), ("mongodb", "find movies"));
+        assert_eq!(dependency_labels("Azure blob", "PUT /thumbnails/0b7e.png"), ("blob", "PUT"));
+    }
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new();
+        metrics.track_request("GET", "/api/movies", Some("/api/movies"), 200, Duration::from_millis(12));
+        metrics.track_dependency("MongoDB", "find movies", "localhost:27017", Duration::from_millis(3), false);
+        metrics.record_upload(2048);
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"cinema_score_http_requests_total{method="GET",route="/api/movies",status="200"} 1"#));
+        assert!(text.contains(r#"cinema_score_dependency_errors_total{dependency="mongodb",operation="find movies"} 1"#));
+        assert!(text.contains("cinema_score_upload_bytes_total 2048"));
+        assert!(text.contains("cinema_score_http_request_duration_seconds_bucket"));
+    }
+}
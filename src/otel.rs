@@ -0,0 +1,131 @@
+use crate::config::{TraceExporter, TracingConfig};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self as sdktrace, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::HeaderMap;
+use rocket::request::Request;
+use rocket::{Orbit, Rocket};
+use serde_json::json;
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::time::UNIX_EPOCH;
+
+/// Builds the tracer for the configured exporter and installs the W3C trace
+/// context propagator. `None` when export is off.
+pub fn tracer(config: &TracingConfig) -> Result<Option<Tracer>, TraceError> {
+    let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+    let builder = TracerProvider::builder().with_config(sdktrace::config().with_resource(resource));
+    let provider = match config.exporter {
+        TraceExporter::None => return Ok(None),
+        TraceExporter::Otlp => {
+            let exporter = opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&config.otlp_endpoint)
+                .build_span_exporter()?;
+            builder.with_batch_exporter(exporter, runtime::Tokio).build()
+        }
+        TraceExporter::Stdout => builder.with_simple_exporter(StdoutExporter).build(),
+    };
+    let tracer = provider.tracer("cinema-score");
+    global::set_tracer_provider(provider);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some(tracer))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap<'a>);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    /// Only the W3C headers: Rocket's map cannot lend out its names, and
+    /// the trace context propagator never asks for them anyway.
+    fn keys(&self) -> Vec<&str> {
+        ["traceparent", "tracestate"].into_iter().filter(|name| self.0.contains(*name)).collect()
+    }
+}
+
+/// The trace a request belongs to, from its `traceparent` and `tracestate`
+/// headers. Empty when they are absent or export is off.
+pub fn parent_context(request: &Request<'_>) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(request.headers())))
+}
+
+/// Writes each finished span to stdout as a line of JSON.
+#[derive(Debug)]
+struct StdoutExporter;
+
+impl SpanExporter for StdoutExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+        let mut stdout = std::io::stdout().lock();
+        for span in batch {
+            let attributes: serde_json::Map<_, _> = span
+                .attributes
+                .iter()
+                .map(|attribute| (attribute.key.to_string(), json!(attribute.value.to_string())))
+                .collect();
+            let start = span.start_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let duration = span.end_time.duration_since(span.start_time).unwrap_or_default();
+            let line = json!({
+                "trace_id": span.span_context.trace_id().to_string(),
+                "span_id": span.span_context.span_id().to_string(),
+                "parent_span_id": span.parent_span_id.to_string(),
+                "name": span.name,
+                "kind": format!("{:?}", span.span_kind),
+                "start_unix_nanos": start.as_nanos() as u64,
+                "duration_ms": duration.as_secs_f64() * 1000.0,
+                "status": format!("{:?}", span.status),
+                "attributes": attributes,
+            });
+            let _ = writeln!(stdout, "{}", line);
+        }
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+/// Sends the spans still buffered when the server stops.
+pub struct TraceShutdown;
+
+#[rocket::async_trait]
+impl Fairing for TraceShutdown {
+    fn info(&self) -> Info {
+        Info {
+            name: "Trace export shutdown",
+            kind: Kind::Shutdown,
+        }
+    }
+
+    async fn on_shutdown(&self, _: &Rocket<Orbit>) {
+        // Flushing blocks, so keep it off the async workers.
+        let _ = rocket::tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeaderExtractor;
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::TraceContextExt;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use rocket::http::{Header, HeaderMap};
+
+    #[test]
+    fn test_traceparent() {
+        let mut headers = HeaderMap::new();
+        headers.add(Header::new("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        let context = TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+        let span = context.span();
+        let span_context = span.span_context();
+        assert!(span_context.is_remote());
+        assert_eq!(span_context.trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span_context.span_id().to_string(), "00f067aa0ba902b7");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::audit::{self, AuditAction, AuditEntry, TargetKind};
+use crate::auth::hash_token;
+use crate::csrf::CsrfVerified;
+use crate::jwt;
+use crate::mail::Message;
+use crate::validation::{self, FieldErrors};
+use crate::{database_error, GenericJsonResponse, ServerData};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::IndexOptions;
+use mongodb::{Collection, IndexModel};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{post, State};
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+const TOKEN_PREFIX: &str = "pr_";
+const RESET_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A password reset link that was mailed out. Only the hash of the token is
+/// kept; a token works once and only until `expires_at`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PasswordReset {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    user_id: ObjectId,
+    token_hash: String,
+    created_at: DateTime,
+    expires_at: DateTime,
+    #[serde(default)]
+    used_at: Option<DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ForgotPasswordRequest {
+    email: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ResetPasswordRequest {
+    token: String,
+    new_password: String,
+}
+
+/// Finds reset tokens by hash, and lets Mongo drop them once expired.
+pub async fn create_indexes(password_resets: Collection<PasswordReset>) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! {"token_hash": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"expires_at": 1})
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build(),
+    ];
+    if let Err(error) = password_resets.create_indexes(indexes, None).await {
+        error!(?error, "Failed to create password reset indexes");
+    }
+}
+
+fn reset_message(to: String, link: &str) -> Message {
+    Message {
+        to,
+        subject: "Reset your Cinema Score password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your Cinema Score account.\n\n\
+             To choose a new password, open this link within the next hour:\n{}\n\n\
+             If it wasn't you, ignore this email and your password will stay the same.",
+            link
+        ),
+    }
+}
+
+fn invalid_token() -> GenericJsonResponse {
+    GenericJsonResponse::error(Status::BadRequest, "Reset link is invalid, expired or already used")
+}
+
+/// Mails a reset link if a user has the given email. The response is the
+/// same either way, so it cannot be used to find out who has an account.
+#[utoipa::path(
+    tag = "account",
+    request_body = ForgotPasswordRequest,
+    responses(
+        (status = 200, description = "A link was mailed if the address belongs to a user"),
+        (status = 422, description = "Not an email address", body = crate::api::Problem),
+    )
+)]
+#[post("/password/forgot", format = "json", data = "<request>")]
+pub async fn forgot_password(request: Json<ForgotPasswordRequest>, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let email = validation::normalize_email(&request.email);
+    let mut errors = FieldErrors::default();
+    errors.add("email", validation::validate_email(&email));
+    if !errors.is_empty() {
+        return errors.into_response();
+    }
+    let response = GenericJsonResponse::ok(&json!({"message": "If an account uses that email, a reset link is on its way"}));
+
+    let user_id = match server_data.users.find_one(doc! {"email": &email}, None).await {
+        Ok(Some(user)) => user.id,
+        Ok(None) => None,
+        Err(error) => return database_error(error),
+    };
+    let Some(user_id) = user_id else {
+        return response;
+    };
+    // Only the newest link works.
+    if let Err(error) = server_data.password_resets.delete_many(doc! {"user_id": user_id, "used_at": null}, None).await {
+        return database_error(error);
+    }
+    let token = format!("{}{}{}", TOKEN_PREFIX, Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let created_at = DateTime::now();
+    let reset = PasswordReset {
+        id: None,
+        user_id,
+        token_hash: hash_token(&token),
+        created_at,
+        expires_at: DateTime::from_millis(created_at.timestamp_millis() + RESET_TOKEN_TTL.as_millis() as i64),
+        used_at: None,
+    };
+    if let Err(error) = server_data.password_resets.insert_one(reset, None).await {
+        return database_error(error);
+    }
+
+    // Sent in the background so the response time does not give away
+    // whether the address belongs to an account.
+    let link = format!("{}/reset-password?token={}", server_data.public_url, token);
+    let message = reset_message(email, &link);
+    let mailer = server_data.mailer.clone();
+    tokio::spawn(async move {
+        if let Err(error) = mailer.send(&message).await {
+            error!(%error, "Failed to send password reset mail");
+        }
+    });
+    response
+}
+
+/// Sets a new password and logs the user out everywhere.
+#[utoipa::path(
+    tag = "account",
+    request_body = ResetPasswordRequest,
+    responses(
+        (status = 200, description = "Password changed"),
+        (status = 400, description = "The reset link is invalid, expired or used", body = crate::api::Problem),
+        (status = 422, description = "The new password is too weak", body = crate::api::Problem),
+    )
+)]
+#[post("/password/reset", format = "json", data = "<request>")]
+pub async fn reset_password(request: Json<ResetPasswordRequest>, client_ip: Option<IpAddr>, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let filter = doc! {"token_hash": hash_token(&request.token), "used_at": null};
+    let reset = match server_data.password_resets.find_one(filter, None).await {
+        Ok(Some(reset)) => reset,
+        Ok(None) => return invalid_token(),
+        Err(error) => return database_error(error),
+    };
+    // Mongo removes expired documents only periodically.
+    if reset.expires_at < DateTime::now() {
+        return invalid_token();
+    }
+    let user = match server_data.users.find_one(doc! {"_id": reset.user_id}, None).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return invalid_token(),
+        Err(error) => return database_error(error),
+    };
+    // Checked before the token is used up, so a rejected password can be retried.
+    let mut errors = FieldErrors::default();
+    errors.add("new_password", validation::validate_password(&request.new_password, &user.name));
+    if !errors.is_empty() {
+        return errors.into_response();
+    }
+
+    let claim = doc! {"$set": {"used_at": DateTime::now()}};
+    match server_data.password_resets.update_one(doc! {"_id": reset.id, "used_at": null}, claim, None).await {
+        Ok(result) if result.modified_count == 0 => return invalid_token(),
+        Ok(_) => (),
+        Err(error) => return database_error(error),
+    }
+
+    // A new session id logs out every browser; proving access to the mailbox
+    // also lifts a lockout.
+    let update = doc! {
+        "$set": {"password": &request.new_password, "uuid": Uuid::new_v4().to_string(), "failed_logins": 0, "password_unset": false},
+        "$unset": {"locked_until": ""},
+    };
+    if let Err(error) = server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        return database_error(error);
+    }
+    audit::record(AuditEntry::new(AuditAction::PasswordChanged, &user, client_ip).target(TargetKind::User, user.id), server_data).await;
+    if let Err(response) = jwt::revoke_user_tokens(user.id, server_data).await {
+        return response;
+    }
+    GenericJsonResponse::ok(&json!({"message": "Password changed, log in with your new password", "redirectPath": "/login"}))
+}
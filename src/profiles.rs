@@ -0,0 +1,219 @@
+use crate::auth::Authenticated;
+use crate::csrf::CsrfVerified;
+use crate::{database_error, movies_in_order, user_by_name, GenericJsonResponse, MovieResponse, ServerData, User};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::FindOptions;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, put, State};
+use std::collections::{BTreeMap, HashMap};
+use tracing::warn;
+use utoipa::ToSchema;
+
+const RECENT_RATINGS: usize = 5;
+const RECENT_REVIEWS: i64 = 5;
+
+/// Per-user choices about what the public profile reveals.
+#[derive(Default, Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct PrivacySettings {
+    #[serde(default)]
+    pub hide_ratings: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct RatedMovie {
+    movie: MovieResponse,
+    rating: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct ReviewedMovie {
+    movie: MovieResponse,
+    rating: Option<f64>,
+    review: String,
+    /// As RFC 3339.
+    posted_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct RatingStats {
+    num_ratings: usize,
+    avg_rating_given: Option<f64>,
+    rating_distribution: BTreeMap<u8, usize>,
+    recent_ratings: Vec<RatedMovie>,
+    recent_reviews: Vec<ReviewedMovie>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct ProfileResponse {
+    name: String,
+    joined: Option<String>,
+    ratings_hidden: bool,
+    ratings: Option<RatingStats>,
+    num_created_movies: usize,
+    created_movies: Vec<MovieResponse>,
+    num_following: usize,
+    num_followers: u64,
+}
+
+/// When the account was created. Users registered before `created_at`
+/// existed fall back to the timestamp embedded in their ObjectId.
+pub fn join_date(user: &User) -> Option<DateTime> {
+    user.created_at
+        .or_else(|| user.id.map(|id| DateTime::from_system_time(id.timestamp().to_system_time())))
+}
+
+/// Counts ratings per star, rounding to the nearest whole star in 1..=5.
+fn rating_distribution(ratings: &[(ObjectId, f64)]) -> BTreeMap<u8, usize> {
+    let mut distribution: BTreeMap<u8, usize> = (1..=5).map(|star| (star, 0)).collect();
+    for &(_, rating) in ratings {
+        let star = rating.round().clamp(1.0, 5.0) as u8;
+        *distribution.entry(star).or_default() += 1;
+    }
+    distribution
+}
+
+fn average(ratings: &[(ObjectId, f64)]) -> Option<f64> {
+    if ratings.is_empty() {
+        return None;
+    }
+    Some(ratings.iter().map(|&(_, rating)| rating).sum::<f64>() / ratings.len() as f64)
+}
+
+async fn rating_stats(user: &User, server_data: &ServerData) -> Result<RatingStats, GenericJsonResponse> {
+    // `movie_ratings` is appended to as ratings come in, so the tail is the most recent.
+    let recent: Vec<_> = user.movie_ratings.iter().rev().take(RECENT_RATINGS).collect();
+    let ids: Vec<_> = recent.iter().map(|&&(id, _)| id).collect();
+    let mut movies: HashMap<String, MovieResponse> = movies_in_order(&ids, server_data)
+        .await?
+        .into_iter()
+        .map(|movie| (movie.id.clone(), movie))
+        .collect();
+    let recent_ratings = recent
+        .iter()
+        .filter_map(|&&(id, rating)| movies.remove(&id.to_string()).map(|movie| RatedMovie { movie, rating }))
+        .collect();
+
+    Ok(RatingStats {
+        num_ratings: user.movie_ratings.len(),
+        avg_rating_given: average(&user.movie_ratings),
+        rating_distribution: rating_distribution(&user.movie_ratings),
+        recent_ratings,
+        recent_reviews: recent_reviews(user, server_data).await?,
+    })
+}
+
+/// The user's latest reviews, newest first, skipping movies since deleted.
+async fn recent_reviews(user: &User, server_data: &ServerData) -> Result<Vec<ReviewedMovie>, GenericJsonResponse> {
+    let filter = doc! {"actor": user.id, "kind": "review_posted", "review": {"$type": "string"}};
+    let options = FindOptions::builder().sort(doc! {"created_at": -1}).limit(RECENT_REVIEWS).build();
+    let mut cursor = server_data.activities.find(filter, options).await.map_err(database_error)?;
+    let mut reviews = Vec::new();
+    while cursor.advance().await.map_err(database_error)? {
+        match cursor.deserialize_current() {
+            Ok(activity) => reviews.push(activity),
+            Err(error) => warn!(?error, "Skipping malformed review"),
+        }
+    }
+
+    let ids: Vec<_> = reviews.iter().map(|review| review.movie_id).collect();
+    let movies: HashMap<String, MovieResponse> = movies_in_order(&ids, server_data)
+        .await?
+        .into_iter()
+        .map(|movie| (movie.id.clone(), movie))
+        .collect();
+    Ok(reviews
+        .into_iter()
+        .filter_map(|review| {
+            Some(ReviewedMovie {
+                movie: movies.get(&review.movie_id.to_string())?.clone(),
+                rating: review.rating,
+                posted_at: review.created_at.try_to_rfc3339_string().unwrap_or_default(),
+                review: review.review.unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+/// A user's public profile. Ratings and reviews are left out if the user
+/// hides their ratings, unless they are asking for their own.
+#[utoipa::path(
+    tag = "users",
+    responses(
+        (status = 200, description = "The profile", body = ProfileResponse),
+        (status = 404, description = "No such user", body = crate::api::Problem),
+    )
+)]
+#[get("/users/<name>")]
+pub async fn get_profile(name: &str, auth: Option<Authenticated>, server_data: &State<ServerData>) -> GenericJsonResponse {
+    let user = match user_by_name(name, &server_data.users).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return GenericJsonResponse::error(Status::NotFound, "User not found"),
+        Err(error) => return database_error(error),
+    };
+    let is_self = auth.is_some_and(|auth| auth.user.id.is_some() && auth.user.id == user.id);
+
+    let ratings = if user.privacy.hide_ratings && !is_self {
+        None
+    } else {
+        match rating_stats(&user, server_data).await {
+            Ok(stats) => Some(stats),
+            Err(response) => return response,
+        }
+    };
+    let created_movies = match movies_in_order(&user.created_movies, server_data).await {
+        Ok(movies) => movies,
+        Err(response) => return response,
+    };
+
+    let num_followers = match server_data.users.count_documents(doc! {"following": user.id}, None).await {
+        Ok(count) => count,
+        Err(error) => return database_error(error),
+    };
+
+    GenericJsonResponse::ok(&ProfileResponse {
+        joined: join_date(&user).and_then(|date| date.try_to_rfc3339_string().ok()),
+        name: user.name,
+        ratings_hidden: user.privacy.hide_ratings,
+        ratings,
+        num_created_movies: created_movies.len(),
+        created_movies,
+        num_following: user.following.len(),
+        num_followers,
+    })
+}
+
+#[utoipa::path(
+    tag = "account",
+    request_body = PrivacySettings,
+    responses(
+        (status = 200, description = "Saved"),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[put("/users/me/privacy", format = "json", data = "<privacy>")]
+pub async fn update_privacy(privacy: Json<PrivacySettings>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    let user = auth.user;
+    let update = doc! {"$set": {"privacy.hide_ratings": privacy.hide_ratings}};
+    match server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        Ok(_) => GenericJsonResponse::ok(&privacy.0),
+        Err(error) => database_error(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{average, rating_distribution};
+    use mongodb::bson::oid::ObjectId;
+
+    #[test]
+    fn test_rating_stats() {
+        let ratings = vec![(ObjectId::new(), 5.0), (ObjectId::new(), 4.5), (ObjectId::new(), 1.5), (ObjectId::new(), 1.0)];
+        let distribution = rating_distribution(&ratings);
+        assert_eq!(distribution.values().copied().collect::<Vec<_>>(), vec![1, 1, 0, 0, 2]);
+        assert_eq!(average(&ratings), Some(3.0));
+        assert_eq!(average(&[]), None);
+    }
+}
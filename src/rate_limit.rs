@@ -0,0 +1,345 @@
+use crate::error::ApiError;
+use crate::{ServerData, User};
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument, UpdateOptions};
+use mongodb::{Collection, IndexModel};
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Consecutive failed logins after which the account itself is locked.
+const LOCKOUT_THRESHOLD: u32 = 10;
+const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+const MAX_LOCKOUT_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// Once the in-memory store tracks this many keys, those with no failure
+/// left in the window are dropped.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where failed attempts are kept. The in-memory store is enough for a single
+/// instance; deployments with several instances need a shared one so an
+/// attacker cannot spread attempts across them.
+#[rocket::async_trait]
+pub trait AttemptStore: Send + Sync {
+    /// Timestamps (unix millis) of failures for `key` at or after `since`, oldest first.
+    async fn failures(&self, key: &str, since: u64) -> Result<Vec<u64>, StoreError>;
+    /// Records a failure at `at`, forgetting anything older than `since`.
+    async fn record_failure(&self, key: &str, at: u64, since: u64) -> Result<(), StoreError>;
+    async fn clear(&self, key: &str) -> Result<(), StoreError>;
+}
+
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    failures: Mutex<HashMap<String, Vec<u64>>>,
+}
+
+#[rocket::async_trait]
+impl AttemptStore for MemoryAttemptStore {
+    async fn failures(&self, key: &str, since: u64) -> Result<Vec<u64>, StoreError> {
+        let failures = self.failures.lock().unwrap_or_else(|error| error.into_inner());
+        Ok(failures
+            .get(key)
+            .map(|times| times.iter().copied().filter(|&at| at >= since).collect())
+            .unwrap_or_default())
+    }
+
+    /// Sweeps with this call's `since`, which is right for every key as long
+    /// as all [`Limits`] share a window.
+    async fn record_failure(&self, key: &str, at: u64, since: u64) -> Result<(), StoreError> {
+        let mut failures = self.failures.lock().unwrap_or_else(|error| error.into_inner());
+        if failures.len() > MAX_TRACKED_KEYS {
+            failures.retain(|_, times| times.last().is_some_and(|&last| last >= since));
+        }
+        let times = failures.entry(key.to_string()).or_default();
+        times.retain(|&time| time >= since);
+        times.push(at);
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), StoreError> {
+        let mut failures = self.failures.lock().unwrap_or_else(|error| error.into_inner());
+        failures.remove(key);
+        Ok(())
+    }
+}
+
+/// Keeps failures in a Mongo collection as `{_id: key, failures: [millis],
+/// expires_at}` so every instance sees the same counts. A key is deleted
+/// once its newest failure has left the window; see [`create_indexes`].
+pub struct MongoAttemptStore {
+    attempts: Collection<Document>,
+}
+
+impl MongoAttemptStore {
+    pub fn new(attempts: Collection<Document>) -> Self {
+        MongoAttemptStore { attempts }
+    }
+}
+
+#[rocket::async_trait]
+impl AttemptStore for MongoAttemptStore {
+    async fn failures(&self, key: &str, since: u64) -> Result<Vec<u64>, StoreError> {
+        let Some(entry) = self.attempts.find_one(doc! {"_id": key}, None).await? else {
+            return Ok(Vec::new());
+        };
+        let mut times: Vec<u64> = entry
+            .get_array("failures")
+            .map(|times| times.iter().filter_map(|time| time.as_i64()).map(|time| time as u64).collect())
+            .unwrap_or_default();
+        times.retain(|&time| time >= since);
+        times.sort_unstable();
+        Ok(times)
+    }
+
+    async fn record_failure(&self, key: &str, at: u64, since: u64) -> Result<(), StoreError> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        let expires_at = DateTime::from_millis((at + at.saturating_sub(since)) as i64);
+        let update = doc! {"$push": {"failures": at as i64}, "$set": {"expires_at": expires_at}};
+        self.attempts.update_one(doc! {"_id": key}, update, options).await?;
+        let update = doc! {"$pull": {"failures": {"$lt": since as i64}}};
+        self.attempts.update_one(doc! {"_id": key}, update, None).await?;
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), StoreError> {
+        self.attempts.delete_one(doc! {"_id": key}, None).await?;
+        Ok(())
+    }
+}
+
+/// Lets Mongo delete keys whose failures have all left the window.
+pub async fn create_indexes(attempts: Collection<Document>) {
+    let index = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+        .build();
+    if let Err(error) = attempts.create_index(index, None).await {
+        error!(?error, "Failed to create login attempt indexes");
+    }
+}
+
+/// Sliding-window limit: the first `free_attempts` failures inside `window`
+/// are not delayed, after that each further failure doubles the wait from
+/// `base_delay` up to `max_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub window: Duration,
+    pub free_attempts: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Limits {
+    pub const PER_USERNAME: Limits = Limits {
+        window: Duration::from_secs(15 * 60),
+        free_attempts: 5,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(5 * 60),
+    };
+
+    pub const PER_IP: Limits = Limits {
+        window: Duration::from_secs(15 * 60),
+        free_attempts: 20,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(15 * 60),
+    };
+
+    /// How long to wait before another attempt is allowed, given the failure
+    /// timestamps (unix millis, oldest first) and the current time.
+    pub fn retry_after(&self, failures: &[u64], now: u64) -> Option<Duration> {
+        let since = now.saturating_sub(self.window.as_millis() as u64);
+        let recent: Vec<u64> = failures.iter().copied().filter(|&at| at >= since).collect();
+        if recent.len() < self.free_attempts {
+            return None;
+        }
+        let excess = (recent.len() - self.free_attempts).min(31) as u32;
+        let delay = self.base_delay.saturating_mul(1 << excess).min(self.max_delay);
+        let allowed_at = recent.last()? + delay.as_millis() as u64;
+        (allowed_at > now).then(|| Duration::from_millis(allowed_at - now))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Throttles `/api/v1/sessions` per username and per client IP.
+pub struct LoginLimiter {
+    store: Box<dyn AttemptStore>,
+}
+
+impl LoginLimiter {
+    pub fn new(store: Box<dyn AttemptStore>) -> Self {
+        LoginLimiter { store }
+    }
+
+    fn keys(username: &str, client_ip: Option<IpAddr>) -> Vec<(String, Limits)> {
+        let mut keys = vec![(format!("user:{}", crate::validation::username_key(username)), Limits::PER_USERNAME)];
+        if let Some(ip) = client_ip {
+            keys.push((format!("ip:{}", ip), Limits::PER_IP));
+        }
+        keys
+    }
+
+    /// Fails with the longest wait across all keys that are over their limit.
+    /// Store errors are logged and let the attempt through rather than
+    /// locking everybody out.
+    pub async fn check(&self, username: &str, client_ip: Option<IpAddr>) -> Result<(), RetryLater> {
+        let now = now_millis();
+        let mut wait = None;
+        for (key, limits) in Self::keys(username, client_ip) {
+            let since = now.saturating_sub(limits.window.as_millis() as u64);
+            match self.store.failures(&key, since).await {
+                Ok(failures) => wait = wait.max(limits.retry_after(&failures, now)),
+                Err(error) => error!(?error, key, "Failed to read login attempts"),
+            }
+        }
+        match wait {
+            Some(wait) => Err(RetryLater(wait)),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn record_failure(&self, username: &str, client_ip: Option<IpAddr>) {
+        let now = now_millis();
+        for (key, limits) in Self::keys(username, client_ip) {
+            let since = now.saturating_sub(limits.window.as_millis() as u64);
+            if let Err(error) = self.store.record_failure(&key, now, since).await {
+                error!(?error, key, "Failed to record login attempt");
+            }
+        }
+    }
+
+    /// Forgets the username's failures after a successful login. The IP
+    /// counter is kept so one valid account cannot be used to reset it.
+    pub async fn record_success(&self, username: &str) {
+        let (key, _) = &Self::keys(username, None)[0];
+        if let Err(error) = self.store.clear(key).await {
+            error!(?error, key, "Failed to clear login attempts");
+        }
+    }
+}
+
+/// How long the account is locked for, if at all.
+pub fn locked_for(user: &User) -> Option<Duration> {
+    let locked_until = user.locked_until?.timestamp_millis();
+    let now = DateTime::now().timestamp_millis();
+    (locked_until > now).then(|| Duration::from_millis((locked_until - now) as u64))
+}
+
+/// Lockout length after `failed_logins` consecutive failures: none below the
+/// threshold, then doubling for every further failure.
+fn lockout_duration(failed_logins: u32) -> Option<Duration> {
+    let excess = failed_logins.checked_sub(LOCKOUT_THRESHOLD)?.min(31);
+    Some(LOCKOUT_DURATION.saturating_mul(1 << excess).min(MAX_LOCKOUT_DURATION))
+}
+
+/// Counts the failure with `$inc` so concurrent guesses each count, then
+/// locks the account based on the count the database ended up with.
+pub async fn record_account_failure(user: &User, server_data: &ServerData) {
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let update = doc! {"$inc": {"failed_logins": 1}};
+    let failed_logins = match server_data.users.find_one_and_update(doc! {"_id": user.id}, update, options).await {
+        Ok(Some(updated)) => updated.failed_logins,
+        Ok(None) => return,
+        Err(error) => {
+            error!(?error, "Failed to record failed login");
+            return;
+        }
+    };
+    let Some(duration) = lockout_duration(failed_logins) else {
+        return;
+    };
+    let locked_until = DateTime::from_millis(DateTime::now().timestamp_millis() + duration.as_millis() as i64);
+    if let Err(error) = server_data.users.update_one(doc! {"_id": user.id}, doc! {"$set": {"locked_until": locked_until}}, None).await {
+        error!(?error, "Failed to lock account");
+    }
+}
+
+pub async fn record_account_success(user: &User, server_data: &ServerData) {
+    if user.failed_logins == 0 && user.locked_until.is_none() {
+        return;
+    }
+    let update = doc! {"$set": {"failed_logins": 0}, "$unset": {"locked_until": ""}};
+    if let Err(error) = server_data.users.update_one(doc! {"_id": user.id}, update, None).await {
+        error!(?error, "Failed to reset failed logins");
+    }
+}
+
+/// 429 response telling the client how many seconds to wait.
+#[derive(Debug)]
+pub struct RetryLater(pub Duration);
+
+impl<'r> Responder<'r, 'static> for RetryLater {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        ApiError::from(self).respond_to(request)
+    }
+}
+
+impl From<RetryLater> for ApiError {
+    fn from(retry: RetryLater) -> Self {
+        ApiError::RateLimited(retry.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lockout_duration, AttemptStore, Limits, MemoryAttemptStore, LOCKOUT_DURATION, MAX_TRACKED_KEYS};
+    use std::time::Duration;
+
+    const LIMITS: Limits = Limits {
+        window: Duration::from_secs(60),
+        free_attempts: 3,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn test_retry_after() {
+        let now = 100_000;
+        assert_eq!(LIMITS.retry_after(&[now - 3000, now - 2000], now), None);
+        assert_eq!(LIMITS.retry_after(&[now - 3000, now - 2000, now - 500], now), Some(Duration::from_millis(500)));
+        assert_eq!(LIMITS.retry_after(&[now - 3, now - 2, now - 1, now], now), Some(Duration::from_secs(2)));
+        let many: Vec<u64> = (0..20).map(|i| now - 20 + i).collect();
+        assert_eq!(LIMITS.retry_after(&many, now), Some(Duration::from_millis(10_000 - 1)));
+        // Failures that slid out of the window no longer count.
+        assert_eq!(LIMITS.retry_after(&[1000, 2000, 3000, 4000], now), None);
+    }
+
+    #[test]
+    fn test_lockout_duration() {
+        assert_eq!(lockout_duration(9), None);
+        assert_eq!(lockout_duration(10), Some(LOCKOUT_DURATION));
+        assert_eq!(lockout_duration(11), Some(LOCKOUT_DURATION * 2));
+        assert_eq!(lockout_duration(100), Some(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[rocket::async_test]
+    async fn test_memory_store() {
+        let store = MemoryAttemptStore::default();
+        store.record_failure("user:a", 10, 0).await.unwrap();
+        store.record_failure("user:a", 20, 0).await.unwrap();
+        store.record_failure("user:a", 30, 15).await.unwrap();
+        assert_eq!(store.failures("user:a", 0).await.unwrap(), vec![20, 30]);
+        assert_eq!(store.failures("user:a", 25).await.unwrap(), vec![30]);
+        store.clear("user:a").await.unwrap();
+        assert!(store.failures("user:a", 0).await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_memory_store_sweep() {
+        let store = MemoryAttemptStore::default();
+        for i in 0..=MAX_TRACKED_KEYS {
+            store.record_failure(&format!("user:{}", i), 10, 0).await.unwrap();
+        }
+        store.record_failure("user:late", 100, 50).await.unwrap();
+        let failures = store.failures.lock().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert!(failures.contains_key("user:late"));
+    }
+}
@@ -0,0 +1,317 @@
+use appinsights::telemetry::{EventTelemetry, RemoteDependencyTelemetry, RequestTelemetry, SeverityLevel, Telemetry as _, TraceTelemetry};
+use appinsights::TelemetryClient;
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::Request;
+use rocket::{Data, Orbit, Response, Rocket};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, field, info_span, Instrument, Span};
+
+/// Where telemetry goes. Shared by the request fairing, the database and blob
+/// storage clients, and the handlers.
+pub type Telemetry = Arc<dyn TelemetrySink>;
+
+/// Product events worth counting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    UserRegistered,
+    MovieAdded,
+    RatingSubmitted,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::UserRegistered => "UserRegistered",
+            Event::MovieAdded => "MovieAdded",
+            Event::RatingSubmitted => "RatingSubmitted",
+        }
+    }
+}
+
+pub trait TelemetrySink: Send + Sync {
+    /// A handled request. `route` is the matched route's template, if any.
+    fn track_request(&self, method: &str, path: &str, route: Option<&str>, status: u16, duration: Duration);
+    /// A call to a database or storage service.
+    fn track_dependency(&self, kind: &str, name: &str, target: &str, duration: Duration, success: bool);
+    /// A request that failed on our side; `code` is the problem code.
+    fn track_exception(&self, code: &str, method: &str, route: Option<&str>, status: u16);
+    fn track_event(&self, event: Event, properties: &[(&str, &str)]);
+    /// Sends whatever is buffered.
+    fn flush(&self) {}
+}
+
+/// Drops everything. Used when no instrumentation key is configured, which
+/// keeps tests and local runs offline.
+pub struct NoopSink;
+
+impl TelemetrySink for NoopSink {
+    fn track_request(&self, _: &str, _: &str, _: Option<&str>, _: u16, _: Duration) {}
+    fn track_dependency(&self, _: &str, _: &str, _: &str, _: Duration, _: bool) {}
+    fn track_exception(&self, _: &str, _: &str, _: Option<&str>, _: u16) {}
+    fn track_event(&self, _: Event, _: &[(&str, &str)]) {}
+}
+
+/// Sends to Application Insights in batches.
+pub struct AppInsightsSink(TelemetryClient);
+
+impl AppInsightsSink {
+    /// Must be called inside the Tokio runtime, which runs the submission task.
+    pub fn new(instrumentation_key: &str) -> Self {
+        AppInsightsSink(TelemetryClient::new(instrumentation_key.to_string()))
+    }
+}
+
+impl TelemetrySink for AppInsightsSink {
+    fn track_request(&self, method: &str, path: &str, route: Option<&str>, status: u16, duration: Duration) {
+        let (Ok(method), Ok(uri)) = (http::Method::from_bytes(method.as_bytes()), path.parse::<http::Uri>()) else {
+            return;
+        };
+        let mut telemetry = RequestTelemetry::new(method, uri, duration, status.to_string());
+        if let Some(route) = route {
+            telemetry.properties_mut().insert("route".to_string(), route.to_string());
+        }
+        self.0.track(telemetry);
+    }
+
+    fn track_dependency(&self, kind: &str, name: &str, target: &str, duration: Duration, success: bool) {
+        self.0.track(RemoteDependencyTelemetry::new(name, kind, duration, target, success));
+    }
+
+    /// appinsights 0.2 has no exception item, so these go out as traces with
+    /// error severity.
+    fn track_exception(&self, code: &str, method: &str, route: Option<&str>, status: u16) {
+        let route = route.unwrap_or("unmatched");
+        let mut telemetry = TraceTelemetry::new(format!("{} {} failed with {}: {}", method, route, status, code), SeverityLevel::Error);
+        let properties = telemetry.properties_mut();
+        properties.insert("code".to_string(), code.to_string());
+        properties.insert("route".to_string(), route.to_string());
+        properties.insert("status".to_string(), status.to_string());
+        self.0.track(telemetry);
+    }
+
+    fn track_event(&self, event: Event, properties: &[(&str, &str)]) {
+        let mut telemetry = EventTelemetry::new(event.name());
+        for (key, value) in properties {
+            telemetry.properties_mut().insert(key.to_string(), value.to_string());
+        }
+        self.0.track(telemetry);
+    }
+
+    fn flush(&self) {
+        self.0.flush_channel();
+    }
+}
+
+/// Passes everything on to each sink in turn.
+pub struct Fanout(pub Vec<Telemetry>);
+
+impl TelemetrySink for Fanout {
+    fn track_request(&self, method: &str, path: &str, route: Option<&str>, status: u16, duration: Duration) {
+        self.0.iter().for_each(|sink| sink.track_request(method, path, route, status, duration));
+    }
+
+    fn track_dependency(&self, kind: &str, name: &str, target: &str, duration: Duration, success: bool) {
+        self.0.iter().for_each(|sink| sink.track_dependency(kind, name, target, duration, success));
+    }
+
+    fn track_exception(&self, code: &str, method: &str, route: Option<&str>, status: u16) {
+        self.0.iter().for_each(|sink| sink.track_exception(code, method, route, status));
+    }
+
+    fn track_event(&self, event: Event, properties: &[(&str, &str)]) {
+        self.0.iter().for_each(|sink| sink.track_event(event, properties));
+    }
+
+    fn flush(&self) {
+        self.0.iter().for_each(|sink| sink.flush());
+    }
+}
+
+/// Application Insights if an instrumentation key is configured, otherwise
+/// [`NoopSink`].
+pub fn from_config(instrumentation_key: Option<&str>) -> Telemetry {
+    match instrumentation_key {
+        Some(key) => Arc::new(AppInsightsSink::new(key)),
+        None => Arc::new(NoopSink),
+    }
+}
+
+/// The problem code of a failed response, left by the responder for
+/// [`TelemetryFairing`].
+#[derive(Default)]
+struct FailureCode(Option<String>);
+
+/// Notes the problem code of a server error response so it is reported as
+/// an exception.
+pub fn note_failure(request: &Request<'_>, body: &str) {
+    let code = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body["code"].as_str().map(str::to_string));
+    request.local_cache(|| FailureCode(code));
+}
+
+struct RequestStart(Option<Instant>);
+
+/// Records every request, and an exception for every 5xx response.
+pub struct TelemetryFairing(pub Telemetry);
+
+#[rocket::async_trait]
+impl Fairing for TelemetryFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Telemetry",
+            kind: Kind::Request | Kind::Response | Kind::Shutdown,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let duration = request.local_cache(|| RequestStart(None)).0.map(|start| start.elapsed()).unwrap_or_default();
+        let route = request.route().map(|route| route.uri.to_string());
+        let method = request.method().as_str();
+        let status = response.status().code;
+        self.0.track_request(method, request.uri().path().as_str(), route.as_deref(), status, duration);
+        if status >= 500 {
+            let code = request.local_cache(FailureCode::default).0.as_deref().unwrap_or("internal_server_error");
+            self.0.track_exception(code, method, route.as_deref(), status);
+        }
+    }
+
+    async fn on_shutdown(&self, _: &Rocket<Orbit>) {
+        self.0.flush();
+    }
+}
+
+/// Reports every command the MongoDB driver sends as a dependency named
+/// after the command and its collection, such as `find movies`, and logs it
+/// in a span under the request that sent it.
+pub struct MongoCommandEvents {
+    telemetry: Telemetry,
+    started: Mutex<HashMap<i32, (String, Span)>>,
+}
+
+impl MongoCommandEvents {
+    pub fn new(telemetry: Telemetry) -> Self {
+        MongoCommandEvents {
+            telemetry,
+            started: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn finish(&self, request_id: i32, command_name: &str, address: String, duration: Duration, success: bool) {
+        let started = self.started.lock().ok().and_then(|mut started| started.remove(&request_id));
+        let (name, span) = started.unwrap_or_else(|| (command_name.to_string(), Span::none()));
+        let latency_ms = duration.as_millis() as u64;
+        if !success {
+            span.record("otel.status_code", "ERROR");
+        }
+        span.in_scope(|| debug!(latency_ms, success, "Database call finished"));
+        self.telemetry.track_dependency("MongoDB", &name, &address, duration, success);
+    }
+}
+
+impl CommandEventHandler for MongoCommandEvents {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        // Commands on a collection carry its name under the command's own key.
+        let name = match event.command.get_str(&event.command_name) {
+            Ok(collection) => format!("{} {}", event.command_name, collection),
+            Err(_) => event.command_name,
+        };
+        let span = info_span!(
+            "database",
+            otel.name = %name,
+            otel.kind = "client",
+            otel.status_code = field::Empty,
+            db.system = "mongodb",
+            db.name = %event.db,
+        );
+        if let Ok(mut started) = self.started.lock() {
+            started.insert(event.request_id, (name, span));
+        }
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.finish(event.request_id, &event.command_name, event.connection.address.to_string(), event.duration, true);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.finish(event.request_id, &event.command_name, event.connection.address.to_string(), event.duration, false);
+    }
+}
+
+/// Reports every blob storage call, retries included, as one dependency, and
+/// logs it in a span under the request that made it.
+pub struct BlobDependencyPolicy(pub Telemetry);
+
+impl fmt::Debug for BlobDependencyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BlobDependencyPolicy")
+    }
+}
+
+#[rocket::async_trait]
+impl azure_core::Policy for BlobDependencyPolicy {
+    async fn send(&self, ctx: &azure_core::Context, request: &mut azure_core::Request, next: &[Arc<dyn azure_core::Policy>]) -> azure_core::PolicyResult {
+        let started = Instant::now();
+        let name = format!("{} {}", request.method(), request.url().path());
+        let target = request.url().host_str().unwrap_or_default().to_string();
+        let span = info_span!(
+            "storage",
+            otel.name = %name,
+            otel.kind = "client",
+            otel.status_code = field::Empty,
+            http.method = %request.method(),
+            http.status_code = field::Empty,
+        );
+        let result = next[0].send(ctx, request, &next[1..]).instrument(span.clone()).await;
+        let success = matches!(&result, Ok(response) if response.status().is_success());
+        if let Ok(response) = &result {
+            span.record("http.status_code", response.status() as u16);
+        }
+        if !success {
+            span.record("otel.status_code", "ERROR");
+        }
+        let duration = started.elapsed();
+        let latency_ms = duration.as_millis() as u64;
+        span.in_scope(|| debug!(latency_ms, success, "Storage call finished"));
+        self.0.track_dependency("Azure blob", &name, &target, duration, success);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, MongoCommandEvents, TelemetrySink};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<(String, bool)>>);
+
+    impl TelemetrySink for RecordingSink {
+        fn track_request(&self, _: &str, _: &str, _: Option<&str>, _: u16, _: Duration) {}
+        fn track_dependency(&self, _: &str, name: &str, _: &str, _: Duration, success: bool) {
+            self.0.lock().unwrap().push((name.to_string(), success));
+        }
+        fn track_exception(&self, _: &str, _: &str, _: Option<&str>, _: u16) {}
+        fn track_event(&self, _: Event, _: &[(&str, &str)]) {}
+    }
+
+    #[test]
+    fn test_mongo_dependency_names() {
+        let sink = Arc::new(RecordingSink::default());
+        let events = MongoCommandEvents::new(sink.clone());
+        events.started.lock().unwrap().insert(7, ("find movies".to_string(), tracing::Span::none()));
+        events.finish(7, "find", "localhost:27017".to_string(), Duration::from_millis(3), true);
+        events.finish(8, "ping", "localhost:27017".to_string(), Duration::from_millis(1), false);
+        assert_eq!(*sink.0.lock().unwrap(), vec![("find movies".to_string(), true), ("ping".to_string(), false)]);
+        assert!(events.started.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,406 @@
+use crate::auth::hash_token;
+use crate::jwt::Jwt;
+use crate::tokens::TOKEN_PREFIX;
+use crate::{GenericJsonResponse, ServerData};
+use mongodb::bson::doc;
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::serde::Deserialize;
+use rocket::{get, Build, Data, Response, Rocket};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// Requests over their limit are rerouted here so the original handler never runs.
+pub const LIMITED_PATH: &str = "/api/rate-limited";
+/// Once this many buckets are tracked, those idle for `IDLE_BUCKET_TTL` are
+/// dropped; a dropped bucket simply starts full again.
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long a credential is trusted, or distrusted, before it is looked up again.
+const CREDENTIAL_TTL: Duration = Duration::from_secs(60);
+
+/// Size and refill rate of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct BucketConfig {
+    pub capacity: u32,
+    pub per_minute: u32,
+}
+
+impl BucketConfig {
+    fn refill_per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+/// Routes sharing a budget, matched by path prefix and, optionally, method.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteGroup {
+    pub name: String,
+    pub prefixes: Vec<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub anonymous: BucketConfig,
+    pub authenticated: BucketConfig,
+}
+
+impl RouteGroup {
+    fn matches(&self, method: Method, path: &str) -> bool {
+        let method_matches = self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str()));
+        method_matches && self.prefixes.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// The `rate_limit` section of `Rocket.toml`. Groups are tried in order and
+/// the first match applies; requests matching no group are not limited.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThrottleConfig {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub groups: Vec<RouteGroup>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        let group = |name: &str, prefixes: &[&str], methods: &[&str], anonymous: u32, authenticated: u32| RouteGroup {
+            name: name.to_string(),
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            methods: methods.iter().map(|method| method.to_string()).collect(),
+            anonymous: BucketConfig { capacity: anonymous, per_minute: anonymous },
+            authenticated: BucketConfig { capacity: authenticated, per_minute: authenticated },
+        };
+        ThrottleConfig {
+            enabled: true,
+            groups: vec![
+                group("uploads", &["/api/v1/movies"], &["POST"], 5, 20),
+                group("thumbnails", &["/api/v1/thumbnails"], &[], 120, 600),
+                group("api", &["/api"], &[], 60, 300),
+                group("graphql", &["/graphql"], &[], 20, 120),
+            ],
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, config: BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.refill_per_second()).min(config.capacity as f64);
+        self.updated = now;
+    }
+}
+
+/// Result of charging a request to its buckets, reported in `RateLimit-*` headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    limit: u32,
+    remaining: u32,
+    /// Seconds until the bucket is full again.
+    reset: u64,
+    /// Set when the request was rejected: seconds until one token is available.
+    retry_after: Option<u64>,
+    window: u64,
+}
+
+#[derive(Default)]
+struct RequestDecision(Option<Decision>);
+
+pub struct Throttle {
+    config: ThrottleConfig,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// Whether each credential seen lately belongs to someone, and when that was checked.
+    credentials: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Throttle {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            credentials: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cached_credential(&self, caller: &str, now: Instant) -> Option<bool> {
+        let credentials = self.credentials.lock().unwrap_or_else(|error| error.into_inner());
+        credentials
+            .get(caller)
+            .filter(|(_, checked)| now.saturating_duration_since(*checked) < CREDENTIAL_TTL)
+            .map(|&(valid, _)| valid)
+    }
+
+    fn remember_credential(&self, caller: String, valid: bool, now: Instant) {
+        let mut credentials = self.credentials.lock().unwrap_or_else(|error| error.into_inner());
+        if credentials.len() > MAX_TRACKED_BUCKETS {
+            credentials.retain(|_, (_, checked)| now.saturating_duration_since(*checked) < CREDENTIAL_TTL);
+        }
+        credentials.insert(caller, (valid, now));
+    }
+
+    /// Takes one token from every `(key, config)` bucket, or none at all if
+    /// any of them is empty. The first bucket is the one reported to the client.
+    fn charge(&self, keys: &[(String, BucketConfig)], now: Instant) -> Option<Decision> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|error| error.into_inner());
+        if buckets.len() > MAX_TRACKED_BUCKETS {
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE_BUCKET_TTL);
+        }
+
+        let mut retry_after: Option<f64> = None;
+        for (key, config) in keys {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket { tokens: config.capacity as f64, updated: now });
+            bucket.refill(*config, now);
+            if bucket.tokens < 1.0 {
+                let wait = (1.0 - bucket.tokens) / config.refill_per_second();
+                retry_after = Some(retry_after.map_or(wait, |other| other.max(wait)));
+            }
+        }
+        if retry_after.is_none() {
+            for (key, _) in keys {
+                if let Some(bucket) = buckets.get_mut(key) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+        }
+
+        let (key, config) = keys.first()?;
+        let tokens = buckets.get(key).map_or(0.0, |bucket| bucket.tokens);
+        let rate = config.refill_per_second();
+        Some(Decision {
+            limit: config.capacity,
+            remaining: tokens.floor().max(0.0) as u32,
+            reset: ((config.capacity as f64 - tokens) / rate).ceil() as u64,
+            retry_after: retry_after.map(|wait| wait.ceil().max(1.0) as u64),
+            window: (config.capacity as f64 / rate).ceil() as u64,
+        })
+    }
+
+    /// Charges a request to its buckets. Callers whose session or API token
+    /// checks out get their own larger bucket, under a per-IP ceiling at the
+    /// authenticated rate; anyone else, forged credentials included, shares
+    /// the anonymous per-IP bucket. A credential not already in the cache is
+    /// only looked up once the anonymous bucket has admitted the request, so
+    /// forging credentials costs as much as any anonymous request.
+    async fn decide(&self, group: &RouteGroup, request: &Request<'_>) -> Option<Decision> {
+        let ip = request.client_ip().map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+        let anonymous = [(format!("{}:ip:{}", group.name, ip), group.anonymous)];
+        let Some(caller) = caller(request) else {
+            return self.charge(&anonymous, Instant::now());
+        };
+        let authenticated = [
+            (format!("{}:{}", group.name, caller), group.authenticated),
+            (format!("{}:ip-authenticated:{}", group.name, ip), group.authenticated),
+        ];
+        match self.cached_credential(&caller, Instant::now()) {
+            Some(true) => return self.charge(&authenticated, Instant::now()),
+            Some(false) => return self.charge(&anonymous, Instant::now()),
+            None => {}
+        }
+        let decision = self.charge(&anonymous, Instant::now());
+        if decision.is_some_and(|decision| decision.retry_after.is_some()) {
+            return decision;
+        }
+        let valid = verify(request).await;
+        let now = Instant::now();
+        self.remember_credential(caller, valid, now);
+        if valid {
+            self.charge(&authenticated, now)
+        } else {
+            decision
+        }
+    }
+}
+
+/// The credential a request carries, as a bucket key.
+fn caller(request: &Request<'_>) -> Option<String> {
+    match (request.headers().get_one("Authorization"), request.cookies().get("id")) {
+        (Some(authorization), _) => Some(format!("token:{}", hash_token(authorization))),
+        (None, Some(session)) => Some(format!("session:{}", session.value())),
+        (None, None) => None,
+    }
+}
+
+/// Whether the request's credential belongs to someone: a live API token, a
+/// validly signed access token in JWT mode, or a session id. Cheaper than
+/// the `Authenticated` guard, which also loads the user and checks scopes.
+async fn verify(request: &Request<'_>) -> bool {
+    let Some(server_data) = request.rocket().state::<ServerData>() else {
+        return false;
+    };
+    let lookup = match request.headers().get_one("Authorization") {
+        Some(authorization) => {
+            let Some(token) = authorization.strip_prefix("Bearer ").map(str::trim) else {
+                return false;
+            };
+            let jwt = request.rocket().state::<Jwt>().filter(|jwt| jwt.enabled());
+            match jwt {
+                Some(jwt) if !token.starts_with(TOKEN_PREFIX) => return jwt.verify(token).is_ok(),
+                _ => server_data.api_tokens.count_documents(doc! {"token_hash": hash_token(token), "revoked_at": null}, None).await,
+            }
+        }
+        None => {
+            let Some(session) = request.cookies().get("id") else {
+                return false;
+            };
+            server_data.users.count_documents(doc! {"uuid": session.value()}, None).await
+        }
+    };
+    match lookup {
+        Ok(count) => count > 0,
+        Err(error) => {
+            error!(?error, "Failed to check credential for rate limiting");
+            false
+        }
+    }
+}
+
+/// Fairing that applies the token-bucket limits from the `rate_limit`
+/// configuration to every request.
+pub struct ThrottleFairing;
+
+#[rocket::async_trait]
+impl Fairing for ThrottleFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "API rate limiting",
+            kind: Kind::Ignite | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = if rocket.figment().find_value("rate_limit").is_ok() {
+            match rocket.figment().extract_inner::<ThrottleConfig>("rate_limit") {
+                Ok(config) => config,
+                Err(error) => {
+                    error!(%error, "Invalid rate_limit configuration");
+                    return Err(rocket);
+                }
+            }
+        } else {
+            ThrottleConfig::default()
+        };
+        Ok(rocket.manage(Throttle::new(config)))
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(throttle) = request.rocket().state::<Throttle>() else {
+            return;
+        };
+        if !throttle.config.enabled {
+            return;
+        }
+        let path = request.uri().path().to_string();
+        let Some(group) = throttle.config.groups.iter().find(|group| group.matches(request.method(), &path)) else {
+            return;
+        };
+        let decision = throttle.decide(group, request).await;
+        request.local_cache(|| RequestDecision(decision));
+        if decision.is_some_and(|decision| decision.retry_after.is_some()) {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(LIMITED_PATH).expect("valid rate limit path"));
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let RequestDecision(Some(decision)) = request.local_cache(RequestDecision::default) else {
+            return;
+        };
+        response.set_raw_header("RateLimit-Limit", decision.limit.to_string());
+        response.set_raw_header("RateLimit-Remaining", decision.remaining.to_string());
+        response.set_raw_header("RateLimit-Reset", decision.reset.to_string());
+        response.set_raw_header("RateLimit-Policy", format!("{};w={}", decision.limit, decision.window));
+        if let Some(retry_after) = decision.retry_after {
+            response.set_raw_header("Retry-After", retry_after.to_string());
+        }
+    }
+}
+
+pub struct TooManyRequests;
+
+impl<'r> Responder<'r, 'static> for TooManyRequests {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = match request.local_cache(RequestDecision::default) {
+            RequestDecision(Some(Decision { retry_after: Some(_), .. })) => Status::TooManyRequests,
+            // Only reachable by requesting the path directly.
+            _ => Status::NotFound,
+        };
+        let error = if status == Status::TooManyRequests { "Too many requests, try again later" } else { "Not found" };
+        GenericJsonResponse::error(status, error).respond_to(request)
+    }
+}
+
+#[get("/api/rate-limited")]
+pub fn rate_limited() -> TooManyRequests {
+    TooManyRequests
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BucketConfig, Throttle, ThrottleConfig};
+    use rocket::http::Method;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_default_groups() {
+        let config = ThrottleConfig::default();
+        let group = |method, path| config.groups.iter().find(|group| group.matches(method, path)).map(|group| group.name.as_str());
+        assert_eq!(group(Method::Post, "/api/v1/movies"), Some("uploads"));
+        assert_eq!(group(Method::Get, "/api/v1/movies"), Some("api"));
+        assert_eq!(group(Method::Post, "/graphql"), Some("graphql"));
+        assert_eq!(group(Method::Get, "/movies"), None);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let throttle = Throttle::new(ThrottleConfig::default());
+        let config = BucketConfig { capacity: 2, per_minute: 60 };
+        let keys = vec![("api:ip:1".to_string(), config)];
+        let start = Instant::now();
+
+        let first = throttle.charge(&keys, start).unwrap();
+        assert_eq!((first.limit, first.remaining, first.retry_after), (2, 1, None));
+        let second = throttle.charge(&keys, start).unwrap();
+        assert_eq!((second.remaining, second.reset, second.retry_after), (0, 2, None));
+        let third = throttle.charge(&keys, start).unwrap();
+        assert_eq!(third.retry_after, Some(1));
+        let later = throttle.charge(&keys, start + Duration::from_secs(1)).unwrap();
+        assert_eq!(later.retry_after, None);
+    }
+
+    #[test]
+    fn test_all_buckets_must_allow() {
+        let throttle = Throttle::new(ThrottleConfig::default());
+        let small = BucketConfig { capacity: 1, per_minute: 1 };
+        let large = BucketConfig { capacity: 10, per_minute: 10 };
+        let now = Instant::now();
+        let keys = vec![("a".to_string(), large), ("b".to_string(), small)];
+
+        assert_eq!(throttle.charge(&keys, now).unwrap().retry_after, None);
+        let rejected = throttle.charge(&keys, now).unwrap();
+        assert_eq!(rejected.retry_after, Some(60));
+        // The rejected request did not use up a token from the larger bucket.
+        assert_eq!(rejected.remaining, 9);
+    }
+
+    #[test]
+    fn test_credential_cache() {
+        let throttle = Throttle::new(ThrottleConfig::default());
+        let now = Instant::now();
+        assert_eq!(throttle.cached_credential("session:forged", now), None);
+        throttle.remember_credential("session:forged".to_string(), false, now);
+        assert_eq!(throttle.cached_credential("session:forged", now + Duration::from_secs(30)), Some(false));
+        assert_eq!(throttle.cached_credential("session:forged", now + Duration::from_secs(60)), None);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::audit::{self, AuditAction, AuditEntry, TargetKind};
+use crate::auth::{hash_token, Authenticated, Scope};
+use crate::csrf::CsrfVerified;
+use crate::{database_error, GenericJsonResponse, ServerData};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{FindOptions, IndexOptions};
+use mongodb::{Collection, IndexModel};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, get, post, State};
+use std::net::IpAddr;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub const TOKEN_PREFIX: &str = "cs_";
+const MAX_NAME_LEN: usize = 100;
+
+/// A personal API token. Only the hash of the secret is stored; the secret
+/// itself is shown once, when the token is created.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    name: String,
+    pub scope: Scope,
+    token_hash: String,
+    /// First characters of the secret, so users can tell their tokens apart.
+    hint: String,
+    created_at: DateTime,
+    #[serde(default)]
+    last_used_at: Option<DateTime>,
+    #[serde(default)]
+    revoked_at: Option<DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct TokenCreateRequest {
+    name: String,
+    scope: Scope,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+struct TokenResponse {
+    id: String,
+    name: String,
+    scope: Scope,
+    hint: String,
+    created_at: String,
+    last_used_at: Option<String>,
+    revoked: bool,
+    /// Only present in the response to creating the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl From<ApiToken> for TokenResponse {
+    fn from(token: ApiToken) -> Self {
+        TokenResponse {
+            id: token.id.map(|id| id.to_hex()).unwrap_or_default(),
+            name: token.name,
+            scope: token.scope,
+            hint: token.hint,
+            created_at: token.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            last_used_at: token.last_used_at.and_then(|date| date.try_to_rfc3339_string().ok()),
+            revoked: token.revoked_at.is_some(),
+            token: None,
+        }
+    }
+}
+
+/// Tokens are looked up by hash on every request.
+pub async fn create_indexes(api_tokens: Collection<ApiToken>) {
+    let index = IndexModel::builder()
+        .keys(doc! {"token_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    if let Err(error) = api_tokens.create_index(index, None).await {
+        error!(?error, "Failed to create API token indexes");
+    }
+}
+
+fn generate_secret() -> String {
+    format!("{}{}{}", TOKEN_PREFIX, Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+#[utoipa::path(
+    tag = "tokens",
+    request_body = TokenCreateRequest,
+    responses(
+        (status = 201, description = "The token; its secret is only shown here", body = TokenResponse),
+        (status = 422, description = "The name is empty or too long", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[post("/tokens", format = "json", data = "<request>")]
+pub async fn create_token(request: Json<TokenCreateRequest>, client_ip: Option<IpAddr>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let name = request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return GenericJsonResponse::error(Status::UnprocessableEntity, "Token name must be between 1 and 100 characters");
+    }
+    let Some(user_id) = auth.user.id else {
+        return GenericJsonResponse::error(Status::InternalServerError, "User has no id");
+    };
+
+    let secret = generate_secret();
+    let mut token = ApiToken {
+        id: Some(ObjectId::new()),
+        user_id,
+        name: name.to_string(),
+        scope: request.scope,
+        token_hash: hash_token(&secret),
+        hint: secret[..TOKEN_PREFIX.len() + 4].to_string(),
+        created_at: DateTime::now(),
+        last_used_at: None,
+        revoked_at: None,
+    };
+    if let Err(error) = server_data.api_tokens.insert_one(&token, None).await {
+        return database_error(error);
+    }
+    let entry = AuditEntry::new(AuditAction::TokenCreated, &auth.user, client_ip)
+        .target(TargetKind::ApiToken, token.id)
+        .changes(doc! {}, doc! {"name": &token.name, "scope": token.scope.as_str()});
+    audit::record(entry, server_data).await;
+    token.token_hash.clear();
+    let mut response = TokenResponse::from(token);
+    response.token = Some(secret);
+    let mut response = GenericJsonResponse::ok(&response);
+    response.status = Status::Created;
+    response
+}
+
+#[utoipa::path(
+    tag = "tokens",
+    responses(
+        (status = 200, description = "The caller's tokens, newest first", body = [TokenResponse]),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[get("/tokens")]
+pub async fn get_tokens(auth: Authenticated, server_data: &State<ServerData>) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let options = FindOptions::builder().sort(doc! {"created_at": -1}).build();
+    let mut cursor = match server_data.api_tokens.find(doc! {"user_id": auth.user.id}, options).await {
+        Ok(cursor) => cursor,
+        Err(error) => return database_error(error),
+    };
+    let mut tokens = Vec::new();
+    loop {
+        match cursor.advance().await {
+            Ok(true) => match cursor.deserialize_current() {
+                Ok(token) => tokens.push(TokenResponse::from(token)),
+                Err(error) => warn!(?error, "Skipping malformed API token"),
+            },
+            Ok(false) => break,
+            Err(error) => return database_error(error),
+        }
+    }
+    GenericJsonResponse::ok(&tokens)
+}
+
+#[utoipa::path(
+    tag = "tokens",
+    responses(
+        (status = 200, description = "Revoked"),
+        (status = 404, description = "No such token", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[delete("/tokens/<id>")]
+pub async fn revoke_token(id: &str, client_ip: Option<IpAddr>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let Ok(id) = ObjectId::parse_str(id) else {
+        return GenericJsonResponse::error(Status::NotFound, "API token not found");
+    };
+    let filter = doc! {"_id": id, "user_id": auth.user.id, "revoked_at": null};
+    let update = doc! {"$set": {"revoked_at": DateTime::now()}};
+    match server_data.api_tokens.update_one(filter, update, None).await {
+        Ok(result) if result.matched_count == 0 => GenericJsonResponse::error(Status::NotFound, "API token not found"),
+        Ok(_) => {
+            audit::record(AuditEntry::new(AuditAction::TokenRevoked, &auth.user, client_ip).target(TargetKind::ApiToken, Some(id)), server_data).await;
+            GenericJsonResponse::ok(&serde_json::json!({"message": "API token revoked"}))
+        }
+        Err(error) => database_error(error),
+    }
+}
@@ -0,0 +1,474 @@
+use crate::audit::{self, AuditAction, AuditEntry};
+use crate::auth::{hash_token, Authenticated, Scope};
+use crate::csrf::CsrfVerified;
+use crate::error::ApiError;
+use crate::rate_limit::{self, RetryLater};
+use crate::{complete_login, database_error, jwt, GenericJsonResponse, ServerData, User};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use mongodb::{Collection, IndexModel};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{delete, post, State};
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+const ISSUER: &str = "Cinema Score";
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+/// Codes from one step before or after the current one are accepted, for
+/// clocks that are slightly off.
+const SKEW: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const CHALLENGE_PREFIX: &str = "tf_";
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+const MAX_CHALLENGE_ATTEMPTS: u32 = 5;
+
+/// A user's authenticator app. Until the first code is verified, `enabled` is
+/// false and login does not ask for a code.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TwoFactor {
+    /// Base32, as shown to the user.
+    secret: String,
+    enabled: bool,
+    /// Hashes of the unused recovery codes.
+    #[serde(default)]
+    recovery_codes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    enabled_at: Option<DateTime>,
+    /// The newest step a code was accepted for, so a code works only once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used_step: Option<i64>,
+}
+
+impl TwoFactor {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+/// A login that passed the password check and waits for a code. Only the
+/// hash of the token handed to the client is stored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Challenge {
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    user_id: ObjectId,
+    token_hash: String,
+    created_at: DateTime,
+    expires_at: DateTime,
+    #[serde(default)]
+    attempts: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct CodeRequest {
+    code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct DisableRequest {
+    password: String,
+    code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct LoginCodeRequest {
+    pending_token: String,
+    code: String,
+}
+
+/// A code that matched, and what has to be used up so it cannot match again.
+#[derive(Debug, PartialEq)]
+enum Verified {
+    Totp(i64),
+    Recovery(String),
+}
+
+/// Finds challenges by hash, and lets Mongo drop them once expired.
+pub async fn create_indexes(challenges: Collection<Challenge>) {
+    let indexes = [
+        IndexModel::builder()
+            .keys(doc! {"token_hash": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"expires_at": 1})
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build(),
+    ];
+    if let Err(error) = challenges.create_indexes(indexes, None).await {
+        error!(?error, "Failed to create two-factor challenge indexes");
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+fn totp(secret: Vec<u8>, account_name: &str) -> TOTP {
+    TOTP::new_unchecked(Algorithm::SHA1, DIGITS, SKEW as u8, STEP, secret, Some(ISSUER.to_string()), account_name.to_string())
+}
+
+/// The step a TOTP code belongs to, if it is valid at `now`.
+fn matching_step(totp: &TOTP, code: &str, now: u64) -> Option<i64> {
+    let current = now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .find(|step| totp.generate(step * STEP) == code)
+        .map(|step| step as i64)
+}
+
+/// Recovery codes are shown as `xxxxx-xxxxx` but accepted without the dash
+/// and in any case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_lowercase()
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let random = Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &random[..5], &random[5..10])
+        })
+        .collect()
+}
+
+/// Checks a six-digit code from the authenticator app or a recovery code.
+fn check_code(two_factor: &TwoFactor, account_name: &str, code: &str, now: u64) -> Option<Verified> {
+    let code = code.trim();
+    if code.len() == DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+        let secret = Secret::Encoded(two_factor.secret.clone()).to_bytes().ok()?;
+        let step = matching_step(&totp(secret, account_name), code, now)?;
+        return (two_factor.last_used_step < Some(step)).then_some(Verified::Totp(step));
+    }
+    let hash = hash_token(&normalize_recovery_code(code));
+    two_factor.recovery_codes.contains(&hash).then_some(Verified::Recovery(hash))
+}
+
+/// Checks a code for a user with two-factor authentication enabled and uses it
+/// up. The update is conditional, so of two requests racing with the same
+/// code only one succeeds.
+async fn verify_code(user: &User, code: &str, server_data: &ServerData) -> Result<bool, GenericJsonResponse> {
+    let Some(two_factor) = user.two_factor.as_ref().filter(|two_factor| two_factor.enabled) else {
+        return Ok(false);
+    };
+    let (filter, update) = match check_code(two_factor, &user.name, code, unix_time()) {
+        None => return Ok(false),
+        Some(Verified::Totp(step)) => (
+            doc! {"_id": user.id, "$or": [{"two_factor.last_used_step": null}, {"two_factor.last_used_step": {"$lt": step}}]},
+            doc! {"$set": {"two_factor.last_used_step": step}},
+        ),
+        Some(Verified::Recovery(hash)) => (
+            doc! {"_id": user.id, "two_factor.recovery_codes": &hash},
+            doc! {"$pull": {"two_factor.recovery_codes": &hash}},
+        ),
+    };
+    match server_data.users.update_one(filter, update, None).await {
+        Ok(result) => Ok(result.modified_count == 1),
+        Err(error) => Err(database_error(error)),
+    }
+}
+
+/// Starts the second step of a login. The response tells the client to ask
+/// for a code and carries the token to send it with.
+pub async fn begin_challenge(user: &User, server_data: &ServerData) -> GenericJsonResponse {
+    match create_challenge(user, server_data).await {
+        Ok(token) => GenericJsonResponse::ok(&json!({
+            "two_factor_required": true,
+            "pending_token": token,
+            "expires_in": CHALLENGE_TTL.as_secs(),
+        })),
+        Err(response) => response,
+    }
+}
+
+/// Stores a challenge for `user` and returns the token that answers it.
+pub async fn create_challenge(user: &User, server_data: &ServerData) -> Result<String, GenericJsonResponse> {
+    let Some(user_id) = user.id else {
+        return Err(GenericJsonResponse::error(Status::InternalServerError, "User has no id"));
+    };
+    let token = format!("{}{}{}", CHALLENGE_PREFIX, Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let created_at = DateTime::now();
+    let challenge = Challenge {
+        id: None,
+        user_id,
+        token_hash: hash_token(&token),
+        created_at,
+        expires_at: DateTime::from_millis(created_at.timestamp_millis() + CHALLENGE_TTL.as_millis() as i64),
+        attempts: 0,
+    };
+    match server_data.two_factor_challenges.insert_one(challenge, None).await {
+        Ok(_) => Ok(token),
+        Err(error) => Err(database_error(error)),
+    }
+}
+
+/// Generates a new secret. Two-factor authentication stays off until
+/// [`verify_enrollment`] sees a code from it; enrolling again before that
+/// replaces the secret.
+#[utoipa::path(
+    tag = "account",
+    responses(
+        (status = 200, description = "The secret, as an `otpauth://` URI and in base32"),
+        (status = 409, description = "Two-factor authentication is already enabled", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[post("/users/me/2fa")]
+pub async fn enroll(auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let user = auth.user;
+    if user.two_factor.as_ref().is_some_and(TwoFactor::enabled) {
+        return GenericJsonResponse::error(Status::Conflict, "Two-factor authentication is already enabled");
+    }
+    let Ok(bytes) = Secret::generate_secret().to_bytes() else {
+        return GenericJsonResponse::error(Status::InternalServerError, "Failed to generate a secret");
+    };
+    let totp = totp(bytes, &user.name);
+    let two_factor = TwoFactor {
+        secret: totp.get_secret_base32(),
+        enabled: false,
+        recovery_codes: Vec::new(),
+        enabled_at: None,
+        last_used_step: None,
+    };
+    let two_factor = match mongodb::bson::to_bson(&two_factor) {
+        Ok(two_factor) => two_factor,
+        Err(error) => {
+            error!(?error, "Failed to serialize two-factor settings");
+            return GenericJsonResponse::error(Status::InternalServerError, "Failed to save the secret");
+        }
+    };
+    let filter = doc! {"_id": user.id, "two_factor.enabled": {"$ne": true}};
+    match server_data.users.update_one(filter, doc! {"$set": {"two_factor": two_factor}}, None).await {
+        Ok(result) if result.matched_count == 0 => {
+            return GenericJsonResponse::error(Status::Conflict, "Two-factor authentication is already enabled");
+        }
+        Ok(_) => (),
+        Err(error) => return database_error(error),
+    }
+    GenericJsonResponse::ok(&json!({
+        "otpauth_uri": totp.get_url(),
+        "secret": totp.get_secret_base32(),
+    }))
+}
+
+/// Turns two-factor authentication on once the user proves their app
+/// produces the right codes. The recovery codes are returned only here.
+#[utoipa::path(
+    tag = "account",
+    request_body = CodeRequest,
+    responses(
+        (status = 200, description = "Enabled; the recovery codes are only shown here"),
+        (status = 400, description = "Wrong code, or enrollment not started", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[post("/users/me/2fa/verify", format = "json", data = "<request>")]
+pub async fn verify_enrollment(request: Json<CodeRequest>, client_ip: Option<IpAddr>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let user = auth.user;
+    let two_factor = match &user.two_factor {
+        Some(two_factor) if two_factor.enabled => {
+            return GenericJsonResponse::error(Status::Conflict, "Two-factor authentication is already enabled");
+        }
+        Some(two_factor) => two_factor,
+        None => return GenericJsonResponse::error(Status::BadRequest, "Start two-factor enrollment first"),
+    };
+    // Recovery codes do not exist yet, so only an app code can match.
+    let Some(Verified::Totp(step)) = check_code(two_factor, &user.name, &request.code, unix_time()) else {
+        return GenericJsonResponse::error(Status::BadRequest, "Invalid code");
+    };
+
+    let recovery_codes = generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| hash_token(&normalize_recovery_code(code))).collect();
+    // Matching the secret keeps a concurrent re-enrollment from being
+    // enabled with a code for the old one.
+    let filter = doc! {"_id": user.id, "two_factor.secret": &two_factor.secret, "two_factor.enabled": false};
+    let update = doc! {"$set": {
+        "two_factor.enabled": true,
+        "two_factor.recovery_codes": hashes,
+        "two_factor.enabled_at": DateTime::now(),
+        "two_factor.last_used_step": step,
+    }};
+    match server_data.users.update_one(filter, update, None).await {
+        Ok(result) if result.modified_count == 0 => GenericJsonResponse::error(Status::Conflict, "Two-factor enrollment changed, start again"),
+        Ok(_) => {
+            audit::record(AuditEntry::new(AuditAction::TwoFactorEnabled, &user, client_ip).target(audit::TargetKind::User, user.id), server_data).await;
+            GenericJsonResponse::ok(&json!({"recovery_codes": recovery_codes}))
+        }
+        Err(error) => database_error(error),
+    }
+}
+
+/// Turns two-factor authentication off. Needs both the password and a code,
+/// so a stolen session alone cannot weaken the account.
+#[utoipa::path(
+    tag = "account",
+    request_body = DisableRequest,
+    responses(
+        (status = 200, description = "Disabled"),
+        (status = 403, description = "Wrong password or code, or no password set yet", body = crate::api::Problem),
+    ),
+    security(("session" = []), ("bearer" = []))
+)]
+#[delete("/users/me/2fa", format = "json", data = "<request>")]
+pub async fn disable(request: Json<DisableRequest>, client_ip: Option<IpAddr>, auth: Authenticated, server_data: &State<ServerData>, _csrf: CsrfVerified) -> GenericJsonResponse {
+    if let Err(response) = auth.require(Scope::Admin) {
+        return response;
+    }
+    let user = auth.user;
+    if !user.two_factor.as_ref().is_some_and(TwoFactor::enabled) {
+        return GenericJsonResponse::error(Status::BadRequest, "Two-factor authentication is not enabled");
+    }
+    if user.password_unset {
+        return GenericJsonResponse::error(Status::Forbidden, "Set a password before disabling two-factor authentication");
+    }
+    if !user.password_matches(&request.password) {
+        return GenericJsonResponse::error(Status::Forbidden, "Wrong password");
+    }
+    match verify_code(&user, &request.code, server_data).await {
+        Ok(true) => (),
+        Ok(false) => return GenericJsonResponse::error(Status::Forbidden, "Invalid code"),
+        Err(response) => return response,
+    }
+    let cleanup = async {
+        server_data.users.update_one(doc! {"_id": user.id}, doc! {"$unset": {"two_factor": ""}}, None).await?;
+        server_data.two_factor_challenges.delete_many(doc! {"user_id": user.id}, None).await
+    };
+    if let Err(error) = cleanup.await {
+        return database_error(error);
+    }
+    audit::record(AuditEntry::new(AuditAction::TwoFactorDisabled, &user, client_ip).target(audit::TargetKind::User, user.id), server_data).await;
+    GenericJsonResponse::ok(&json!({"message": "Two-factor authentication disabled"}))
+}
+
+/// Second step of a login: trades the pending token and a code for a
+/// session, or tokens in JWT mode. Wrong codes count towards the account
+/// lockout like wrong passwords.
+#[utoipa::path(
+    tag = "sessions",
+    request_body = LoginCodeRequest,
+    responses(
+        (status = 200, description = "Logged in"),
+        (status = 401, description = "Wrong code, or the login expired", body = crate::api::Problem),
+        (status = 429, description = "The account is locked", body = crate::api::Problem),
+    )
+)]
+#[post("/sessions/2fa", format = "json", data = "<request>")]
+pub async fn complete_challenge(request: Json<LoginCodeRequest>, client_ip: Option<IpAddr>, server_data: &State<ServerData>, jwt: &State<jwt::Jwt>, _csrf: CsrfVerified) -> Result<GenericJsonResponse, RetryLater> {
+    let invalid = || GenericJsonResponse::error(Status::Unauthorized, "Login expired, log in again");
+    let filter = doc! {"token_hash": hash_token(&request.pending_token), "attempts": {"$lt": MAX_CHALLENGE_ATTEMPTS}};
+    let options = mongodb::options::FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let challenge = match server_data.two_factor_challenges.find_one_and_update(filter, doc! {"$inc": {"attempts": 1}}, options).await {
+        Ok(Some(challenge)) => challenge,
+        Ok(None) => return Ok(invalid()),
+        Err(error) => return Ok(database_error(error)),
+    };
+    // Mongo removes expired documents only periodically.
+    if challenge.expires_at < DateTime::now() {
+        return Ok(invalid());
+    }
+    let user = match server_data.users.find_one(doc! {"_id": challenge.user_id}, None).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(invalid()),
+        Err(error) => return Ok(database_error(error)),
+    };
+    if let Some(wait) = rate_limit::locked_for(&user) {
+        return Err(RetryLater(wait));
+    }
+    match verify_code(&user, &request.code, server_data).await {
+        Ok(true) => (),
+        Ok(false) => {
+            audit::record(AuditEntry::new(AuditAction::LoginFailed, &user, client_ip), server_data).await;
+            rate_limit::record_account_failure(&user, server_data).await;
+            let error = ApiError::Other(Status::Unauthorized, "Invalid code".to_string());
+            let mut body = error.body();
+            body["attempts_left"] = json!(MAX_CHALLENGE_ATTEMPTS - challenge.attempts);
+            return Ok(GenericJsonResponse {
+                json: body.to_string(),
+                status: error.status(),
+                cookies: Vec::new(),
+            });
+        }
+        Err(response) => return Ok(response),
+    }
+    if let Err(error) = server_data.two_factor_challenges.delete_one(doc! {"_id": challenge.id}, None).await {
+        error!(?error, "Failed to delete two-factor challenge");
+    }
+    rate_limit::record_account_success(&user, server_data).await;
+    Ok(complete_login(&user, client_ip, jwt, server_data).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rfc_totp() -> TOTP {
+        // The SHA-1 secret from the RFC 6238 test vectors.
+        totp(b"12345678901234567890".to_vec(), "alice")
+    }
+
+    fn two_factor(last_used_step: Option<i64>, recovery_codes: &[&str]) -> TwoFactor {
+        TwoFactor {
+            secret: rfc_totp().get_secret_base32(),
+            enabled: true,
+            recovery_codes: recovery_codes.iter().map(|code| hash_token(&normalize_recovery_code(code))).collect(),
+            enabled_at: None,
+            last_used_step,
+        }
+    }
+
+    #[test]
+    fn test_rfc_vector() {
+        // 94287082 at T=59 in the RFC, truncated to six digits.
+        assert_eq!(rfc_totp().generate(59), "287082");
+        assert_eq!(matching_step(&rfc_totp(), "287082", 59), Some(1));
+    }
+
+    #[test]
+    fn test_skew() {
+        let totp = rfc_totp();
+        assert_eq!(matching_step(&totp, "287082", 59 + STEP), Some(1));
+        assert_eq!(matching_step(&totp, "287082", 59 + 2 * STEP), None);
+        assert_eq!(matching_step(&totp, "000000", 59), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        assert_eq!(check_code(&two_factor(None, &[]), "alice", "287082", 59), Some(Verified::Totp(1)));
+        assert_eq!(check_code(&two_factor(Some(1), &[]), "alice", "287082", 59), None);
+        assert_eq!(check_code(&two_factor(Some(0), &[]), "alice", " 287082 ", 59), Some(Verified::Totp(1)));
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| code.len() == 11 && code.as_bytes()[5] == b'-'));
+
+        let two_factor = two_factor(None, &["abcde-12345"]);
+        let hash = hash_token("abcde12345");
+        assert_eq!(check_code(&two_factor, "alice", "ABCDE-12345", 59), Some(Verified::Recovery(hash.clone())));
+        assert_eq!(check_code(&two_factor, "alice", "abcde12345", 59), Some(Verified::Recovery(hash)));
+        assert_eq!(check_code(&two_factor, "alice", "abcde-00000", 59), None);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let url = rfc_totp().get_url();
+        assert!(url.starts_with("otpauth://totp/Cinema%20Score:alice?"));
+        assert!(url.contains("secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+    }
+}
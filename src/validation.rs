@@ -0,0 +1,180 @@
+use crate::error::ApiError;
+use crate::GenericJsonResponse;
+use rocket::serde::Serialize;
+use std::collections::BTreeMap;
+use unicode_normalization::UnicodeNormalization;
+
+pub const MIN_USERNAME_LEN: usize = 3;
+pub const MAX_USERNAME_LEN: usize = 32;
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 128;
+pub const MAX_EMAIL_LEN: usize = 254;
+
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "password1", "12345678", "123456789", "1234567890", "qwerty123", "qwertyuiop", "iloveyou", "letmein1",
+    "admin123", "welcome1", "passw0rd",
+];
+
+/// Validation messages keyed by the request field they refer to.
+#[derive(Default, Debug, Serialize)]
+pub struct FieldErrors(BTreeMap<&'static str, Vec<String>>);
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &'static str, messages: Vec<String>) {
+        if !messages.is_empty() {
+            self.0.entry(field).or_default().extend(messages);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The first message, for clients that show only one.
+    pub fn first(&self) -> Option<&str> {
+        self.0.values().flatten().next().map(String::as_str)
+    }
+
+    pub fn into_response(self) -> GenericJsonResponse {
+        ApiError::Validation(self).into()
+    }
+}
+
+/// Canonical form a username is stored and displayed in: NFKC-normalized so
+/// look-alike compatibility characters collapse, with surrounding whitespace removed.
+pub fn normalize_username(name: &str) -> String {
+    name.trim().nfkc().collect()
+}
+
+/// Key used to enforce case-insensitive uniqueness of usernames.
+pub fn username_key(name: &str) -> String {
+    normalize_username(name).to_lowercase()
+}
+
+/// Checks a normalized username. Names are restricted to ASCII letters,
+/// digits, `.`, `_` and `-` so they are always safe in a cookie value and a
+/// URL path segment.
+pub fn validate_username(name: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    let len = name.chars().count();
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&len) {
+        errors.push(format!(
+            "Username must be between {} and {} characters",
+            MIN_USERNAME_LEN, MAX_USERNAME_LEN
+        ));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+        errors.push("Username may only contain letters, digits, '.', '_' and '-'".to_string());
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) && len > 0 {
+        errors.push("Username must start with a letter or digit".to_string());
+    }
+    errors
+}
+
+pub fn validate_password(password: &str, username: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    let len = password.chars().count();
+    if len < MIN_PASSWORD_LEN {
+        errors.push(format!("Password must be at least {} characters", MIN_PASSWORD_LEN));
+    }
+    if len > MAX_PASSWORD_LEN {
+        errors.push(format!("Password must be at most {} characters", MAX_PASSWORD_LEN));
+    }
+    if !password.chars().any(char::is_alphabetic) || !password.chars().any(|c| !c.is_alphabetic()) {
+        errors.push("Password must contain a letter and a digit or symbol".to_string());
+    }
+    let lowercase = password.to_lowercase();
+    if !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+        errors.push("Password must not contain the username".to_string());
+    }
+    if COMMON_PASSWORDS.contains(&lowercase.as_str()) {
+        errors.push("Password is too common".to_string());
+    }
+    errors
+}
+
+/// Emails are compared and stored lowercased, so each address can belong to
+/// one user only.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// A deliberately loose check; the reset mail is the real test of an address.
+pub fn validate_email(email: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    if email.len() > MAX_EMAIL_LEN {
+        errors.push(format!("Email must be at most {} characters", MAX_EMAIL_LEN));
+    }
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        }
+        None => false,
+    };
+    if !valid {
+        errors.push("Email address is not valid".to_string());
+    }
+    errors
+}
+
+/// Whether a Mongo error was caused by a unique index rejecting a duplicate.
+pub fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    use mongodb::error::{ErrorKind, WriteFailure};
+    match error.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == 11000,
+        ErrorKind::Command(error) => error.code == 11000,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_email, normalize_username, username_key, validate_email, validate_password, validate_username};
+
+    #[test]
+    fn test_validate_username() {
+        assert!(validate_username("mariusz").is_empty());
+        assert!(validate_username("film_fan-2.0").is_empty());
+        assert!(!validate_username("ab").is_empty());
+        assert!(!validate_username(&"a".repeat(33)).is_empty());
+        assert!(!validate_username("bad=name").is_empty());
+        assert!(!validate_username("bad;name").is_empty());
+        assert!(!validate_username("with space").is_empty());
+        assert!(!validate_username("_leading").is_empty());
+        assert!(!validate_username("").is_empty());
+    }
+
+    #[test]
+    fn test_normalize_username() {
+        assert_eq!(normalize_username("  ｍａｒｉｕｓｚ "), "mariusz");
+        assert_eq!(username_key("Mariusz"), username_key("ＭＡＲＩＵＳＺ"));
+    }
+
+    #[test]
+    fn test_validate_password() {
+        assert!(validate_password("correct-horse-7", "mariusz").is_empty());
+        assert!(!validate_password("f", "mariusz").is_empty());
+        assert!(!validate_password("onlyletters", "mariusz").is_empty());
+        assert!(!validate_password("mariusz-123", "mariusz").is_empty());
+        assert!(!validate_password("Password1", "mariusz").is_empty());
+        assert!(!validate_password(&"a1".repeat(65), "mariusz").is_empty());
+    }
+
+    #[test]
+    fn test_validate_email() {
+        assert_eq!(normalize_email(" Jane@Example.COM "), "jane@example.com");
+        assert!(validate_email("jane@example.com").is_empty());
+        assert!(!validate_email("jane").is_empty());
+        assert!(!validate_email("@example.com").is_empty());
+        assert!(!validate_email("jane@localhost").is_empty());
+        assert!(!validate_email("jane@@example.com").is_empty());
+        assert!(!validate_email("jane doe@example.com").is_empty());
+        assert!(!validate_email(&format!("{}@example.com", "a".repeat(250))).is_empty());
+    }
+}
@@ -14,6 +14,11 @@ function redirectToDeleteMoviePage() {
 	window.location.href = '/delete-movie';
 }
 
+function getCsrfToken() {
+	const csrfCookie = document.cookie.split('; ').find(cookie => cookie.startsWith('csrf_token='));
+	return csrfCookie ? csrfCookie.split('=')[1] : '';
+}
+
 function getUsernameFromCookie () {
 	const usernameCookie = document.cookie.split('; ').find(cookie => cookie.startsWith('username='));
 	return usernameCookie ? usernameCookie.split('=')[1] : null;
@@ -70,6 +75,7 @@ function cleanInterface() {
 async function registerUser(event) {
 	event.preventDefault();
 	const name = document.getElementById('name').value;
+	const email = document.getElementById('email').value || null;
 	const password = document.getElementById('password').value;
 	const confirmPassword = document.getElementById('confirmPassword').value;
 
@@ -78,17 +84,21 @@ async function registerUser(event) {
 		return;
 	}
 
-		fetch('/api/users', {
+		fetch('/api/v1/users', {
 			method: 'POST',
 			headers: {
 				'Content-Type': 'application/json',
+				'X-CSRF-Token': getCsrfToken(),
 			},
-			body: JSON.stringify({ name, password }),
+			body: JSON.stringify({ name, email, password }),
 		})
 		.then(response => response.json())
 		.then(data => {
 			if (data.redirectPath) {
 				window.location.href = data.redirectPath;
+			} else if (data.fields) {
+				const errorContainer = document.getElementById('error-container');
+				errorContainer.textContent = Object.values(data.fields).flat().join(' ');
 			} else if (data.error) {
 				const errorContainer = document.getElementById('error-container');
 				errorContainer.textContent = data.error;
@@ -99,21 +109,84 @@ async function registerUser(event) {
 		});
 }
 
+async function forgotPassword(event) {
+	event.preventDefault();
+	cleanInterface();
+	const email = document.getElementById('email').value;
+
+	fetch('/api/v1/password/forgot', {
+		method: 'POST',
+		headers: {
+			'Content-Type': 'application/json',
+			'X-CSRF-Token': getCsrfToken(),
+		},
+		body: JSON.stringify({ email }),
+	})
+		.then(response => response.json())
+		.then(data => {
+			if (data.message) {
+				document.getElementById('message-container').textContent = data.message;
+			} else if (data.error) {
+				document.getElementById('error-container').textContent = data.error;
+			}
+		})
+		.catch(error => {
+			console.error('Error:', error);
+		});
+}
+
+async function resetPassword(event) {
+	event.preventDefault();
+	const token = new URLSearchParams(window.location.search).get('token');
+	const new_password = document.getElementById('password').value;
+	const confirmPassword = document.getElementById('confirmPassword').value;
+
+	if (new_password !== confirmPassword) {
+		alert("Passwords do not match");
+		return;
+	}
+
+	fetch('/api/v1/password/reset', {
+		method: 'POST',
+		headers: {
+			'Content-Type': 'application/json',
+			'X-CSRF-Token': getCsrfToken(),
+		},
+		body: JSON.stringify({ token, new_password }),
+	})
+		.then(response => response.json())
+		.then(data => {
+			if (data.redirectPath) {
+				window.location.href = data.redirectPath;
+			} else if (data.fields) {
+				document.getElementById('error-container').textContent = Object.values(data.fields).flat().join(' ');
+			} else if (data.error) {
+				document.getElementById('error-container').textContent = data.error;
+			}
+		})
+		.catch(error => {
+			console.error('Error:', error);
+		});
+}
+
 async function login(event) {
 	event.preventDefault();
 	const name = document.getElementById('username').value;
 	const password = document.getElementById('password').value;
 
-	fetch('/api/login', {
+	fetch('/api/v1/sessions', {
 		method: 'POST',
 		headers: {
 			'Content-Type': 'application/json',
+			'X-CSRF-Token': getCsrfToken(),
 		},
 		body: JSON.stringify({ name, password }),
 	})
 		.then(response => response.json())
 		.then(data => {
-			if (data.redirectPath) {
+			if (data.two_factor_required) {
+				showTwoFactorForm(data.pending_token);
+			} else if (data.redirectPath) {
 				window.location.href = data.redirectPath;
 			} else if (data.error) {
 				const errorContainer = document.getElementById('error-container');
@@ -125,14 +198,64 @@ async function login(event) {
 		});
 }
 
+let pendingLoginToken = null;
+
+function showTwoFactorForm(token) {
+	pendingLoginToken = token;
+	document.getElementById('login-form').style.display = 'none';
+	document.getElementById('two-factor-form').style.display = 'block';
+	document.getElementById('code').focus();
+}
+
+// A login through an identity provider that still needs a code comes back
+// to /login with the challenge token in the fragment.
+function resumeTwoFactorLogin() {
+	const token = new URLSearchParams(window.location.hash.slice(1)).get('pending_token');
+	if (token) {
+		history.replaceState(null, '', window.location.pathname);
+		showTwoFactorForm(token);
+	}
+}
+
+async function submitTwoFactorCode(event) {
+	event.preventDefault();
+	const code = document.getElementById('code').value;
+
+	fetch('/api/v1/sessions/2fa', {
+		method: 'POST',
+		headers: {
+			'Content-Type': 'application/json',
+			'X-CSRF-Token': getCsrfToken(),
+		},
+		body: JSON.stringify({ pending_token: pendingLoginToken, code }),
+	})
+		.then(response => response.json())
+		.then(data => {
+			if (data.redirectPath) {
+				window.location.href = data.redirectPath;
+			} else if (data.error) {
+				document.getElementById('two-factor-error-container').textContent = data.error;
+			}
+		})
+		.catch(error => {
+			console.error('Error:', error);
+		});
+}
+
 function logout() {
 	document.cookie.split(';').forEach(cookie => {
 		const cookieName = cookie.split('=')[0].trim();
+		if (cookieName === 'csrf_token') {
+			return;
+		}
 		document.cookie = `${cookieName}=; expires=Thu, 01 Jan 1970 00:00:00 UTC; path=/;`;
 	});
 
-	fetch('/logout', {
-		method: 'POST'
+	fetch('/api/v1/sessions/current', {
+		method: 'DELETE',
+		headers: {
+			'X-CSRF-Token': getCsrfToken(),
+		},
 	})
 		.then(response => {
 			if (response.ok) {
@@ -147,7 +270,7 @@ function logout() {
 }
 
 function fetchMovies() {
-	fetch('/api/movies')
+	fetch('/api/v1/movies')
 		.then(response => response.json())
 		.then(data => {
 			displayMovies(data);
@@ -178,10 +301,11 @@ function addMovie(event) {
 
 
 		// Make a POST request to add a new movie (adjust as needed based on your server-side implementation)
-		fetch('/api/add-movie', {
+		fetch('/api/v1/movies', {
 			method: 'POST',
 			headers: {
 				'Content-Type': 'application/json',
+				'X-CSRF-Token': getCsrfToken(),
 			},
 			body: JSON.stringify(newMovie),
 		})
@@ -221,7 +345,7 @@ function previewImageData(image_url) {
 	if (image_url == "") {
 		return "";
 	}
-	fetch(`/api/thumbnail/${image_url}`)
+	fetch(`/api/v1/thumbnails/${image_url}`)
 		.then(response => response.json())
 		.then(data => {
 			const uint8Array = new Uint8Array(data);
@@ -254,7 +378,7 @@ function displayMovies(movies) {
 			return Promise.resolve(""); // Resolve immediately for empty image URL
 		}
 
-		return fetch(`/api/thumbnail/${image_url}`)
+		return fetch(`/api/v1/thumbnails/${image_url}`)
 			.then(response => response.json())
 			.then(data => {
 				const uint8Array = new Uint8Array(data);
@@ -285,12 +409,12 @@ function displayMovies(movies) {
 		const imagePreviewData = await getPreviewImageData(movie.image_url);
 
 		moviesList.innerHTML += `
-			<div>
+			<div id="movie_${movie.id}">
 				<strong>Title:</strong> ${movie.title} <br>
 				<strong>Author:</strong> ${movie.author} <br>
 				<img class="preview-image" alt="Preview Image" src="${imagePreviewData}"> <br>
-				<strong>Average Rating:</strong> ${movie.avg_rating} <br>
-				<strong>Number of Ratings:</strong> ${movie.num_ratings} <br>
+				<strong>Average Rating:</strong> <span id="avgRating_${movie.id}">${movie.avg_rating}</span> <br>
+				<strong>Number of Ratings:</strong> <span id="numRatings_${movie.id}">${movie.num_ratings}</span> <br>
 				<form id="ratingForm_${movie.id}">
 					<label for="rating_${movie.id}">Rate this movie:</label>
 					<input type="number" name="rating" id="rating_${movie.id}" min="1" max="5">
@@ -301,22 +425,68 @@ function displayMovies(movies) {
 	});
 }
 
+// Keeps the list from fetchMovies up to date as movies are added, deleted and rated.
+function subscribeToMovieEvents() {
+	const events = new EventSource('/api/v1/events');
+	events.addEventListener('movie-added', event => {
+		displayMovies([JSON.parse(event.data)]);
+	});
+	events.addEventListener('movie-deleted', event => {
+		const movie = document.getElementById(`movie_${JSON.parse(event.data).id}`);
+		if (movie) {
+			movie.remove();
+		}
+	});
+	events.addEventListener('rating-changed', event => {
+		const data = JSON.parse(event.data);
+		const avgRating = document.getElementById(`avgRating_${data.id}`);
+		const numRatings = document.getElementById(`numRatings_${data.id}`);
+		if (avgRating && numRatings) {
+			avgRating.textContent = data.avg_rating;
+			numRatings.textContent = data.num_ratings;
+		}
+	});
+	// Too many events were missed to patch the list up.
+	events.addEventListener('lagged', () => {
+		document.getElementById('movies-list').innerHTML = '';
+		fetchMovies();
+	});
+}
+
 function submitRating(event, movieId) {
 	event.preventDefault();
 
 	const formId = `ratingForm_${movieId}`;
-	console.log(formId);
 	const ratingForm = document.getElementById(formId);
 	const ratingInput = ratingForm.querySelector(`#rating_${movieId}`);
 
-	const rating = ratingInput.value;
+	const rating = Number(ratingInput.value);
 
-	console.log(`Movie Id: ${movieId}, Rating: ${rating}`);
+	// The new average arrives through the rating-changed event.
+	fetch(`/api/v1/movies/${movieId}/rating`, {
+		method: 'PUT',
+		headers: {
+			'Content-Type': 'application/json',
+			'X-CSRF-Token': getCsrfToken(),
+		},
+		body: JSON.stringify({ rating }),
+	})
+		.then(response => response.json())
+		.then(data => {
+			if (data.fields) {
+				alert(Object.values(data.fields).flat().join(' '));
+			} else if (data.error) {
+				alert(data.error);
+			}
+		})
+		.catch(error => {
+			console.error('Error:', error);
+		});
 }
 
 function fetchMyMovies() {
 	const username = getUsernameFromCookie();
-	fetch(`/api/movies/${username}`)
+	fetch(`/api/v1/users/${username}/movies`)
 		.then(response => response.json())
 		.then(data => {
 			displayMyMovies(data);
@@ -333,7 +503,7 @@ function displayMyMovies(movies) {
 			return Promise.resolve(""); // Resolve immediately for empty image URL
 		}
 
-		return fetch(`/api/thumbnail/${image_url}`)
+		return fetch(`/api/v1/thumbnails/${image_url}`)
 			.then(response => response.json())
 			.then(data => {
 				const uint8Array = new Uint8Array(data);
@@ -384,3 +554,25 @@ function deleteMovie(event, movieId) {
 	console.log(`Movie Id: ${movieId} will be deleted`);
 }
 
+
+function getListSlugFromPath() {
+	return window.location.pathname.split('/').pop();
+}
+
+function fetchList() {
+	const slug = getListSlugFromPath();
+	fetch(`/api/v1/lists/${slug}`)
+		.then(response => response.json())
+		.then(data => {
+			if (data.error) {
+				const errorContainer = document.getElementById('error-container');
+				errorContainer.textContent = data.error;
+				return;
+			}
+			document.getElementById('list-title').textContent = data.title;
+			document.getElementById('list-owner').textContent = 'Curated by ' + data.owner;
+			document.getElementById('list-description').textContent = data.description;
+			displayMovies(data.movies);
+		})
+		.catch(error => console.error('Error fetching list:', error));
+}